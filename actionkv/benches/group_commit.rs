@@ -0,0 +1,65 @@
+//! Synced inserts per second from several threads, with and without group
+//! commit. Run with `cargo bench --bench group_commit [-- THREADS OPS]`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use libactionkv::{Options, SharedKV, SyncPolicy};
+
+const VALUE: &[u8] = &[b'v'; 100];
+
+fn fresh_store(name: &str) -> (PathBuf, SharedKV) {
+    let dir = std::env::temp_dir().join("actionkv-bench");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    // 两种写法都要落盘才返回，比的是 fsync 怎么分摊
+    let store = SharedKV::open_with(&path, Options::new().sync_policy(SyncPolicy::Always)).unwrap();
+    (path, store)
+}
+
+/// Runs `write(thread, i)` `ops` times on each of `threads` threads and
+/// returns the writes per second.
+fn run<F>(threads: usize, ops: usize, write: F) -> f64
+where
+    F: Fn(usize, usize) + Send + Sync + 'static,
+{
+    let write = Arc::new(write);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let write = write.clone();
+            thread::spawn(move || (0..ops).for_each(|i| write(t, i)))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    (threads * ops) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    // cargo bench 会在参数里带上 --bench
+    let args: Vec<usize> = std::env::args().skip(1).filter_map(|a| a.parse().ok()).collect();
+    let threads = args.first().copied().unwrap_or(8);
+    let ops = args.get(1).copied().unwrap_or(500);
+
+    let (path, store) = fresh_store("locked");
+    let locked = run(threads, ops, move |t, i| {
+        store.insert(format!("{}-{}", t, i).as_bytes(), VALUE).unwrap();
+    });
+    let _ = std::fs::remove_file(path);
+
+    let (path, store) = fresh_store("grouped");
+    let group = store.group_commit();
+    let grouped = run(threads, ops, move |t, i| {
+        group.insert(format!("{}-{}", t, i).as_bytes(), VALUE).unwrap();
+    });
+    let _ = std::fs::remove_file(path);
+
+    println!("{} threads x {} synced inserts", threads, ops);
+    println!("one write and sync per insert: {:>10.0} ops/sec", locked);
+    println!("group commit:                  {:>10.0} ops/sec ({:.1}x)", grouped, grouped / locked);
+}
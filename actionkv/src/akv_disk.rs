@@ -1,3 +1,101 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+use libactionkv::{exit, ActionKvError, Options};
+
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_disk.exe FILE get KEY
+    akv_disk.exe FILE delete KEY
+    akv_disk.exe FILE insert KEY VALUE
+    akv_disk.exe FILE update KEY VALUE
+    akv_disk.exe FILE hint
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_disk FILE get KEY
+    akv_disk FILE delete KEY
+    akv_disk FILE insert KEY VALUE
+    akv_disk FILE update KEY VALUE
+    akv_disk FILE hint
+";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(exit::USAGE);
+}
+
+fn fail<E: Into<ActionKvError>>(err: E) -> ! {
+    let err = err.into();
+    eprintln!("error: {}", err);
+    process::exit(err.exit_code());
+}
+
+fn not_found(key: &str) -> ! {
+    eprintln!("{:?} not found", key);
+    process::exit(exit::NOT_FOUND);
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).unwrap_or_else(|| usage());
+    let action = args.get(2).unwrap_or_else(|| usage()).as_str();
+    if action == "hint" && args.len() == 3 {
+        return write_hint(Path::new(fname));
+    }
+    let key = args.get(3).unwrap_or_else(|| usage());
+    let maybe_value = args.get(4);
+
+    let path = Path::new(fname);
+    // 只读的话不用等写者放锁；写的话先切掉上次崩溃留下的半条记录
+    let mut store = match Options::new().read_only(action == "get").repair_torn_tail(true).open(path) {
+        Ok(store) => store,
+        Err(ActionKvError::Io(err)) if action == "get" && err.kind() == io::ErrorKind::NotFound => not_found(key),
+        Err(err) => fail(err),
+    };
+    store.load().unwrap_or_else(|e| fail(e));
+
+    match action {
+        "get" => match store.get(key.as_bytes()).unwrap_or_else(|e| fail(e)) {
+            None => not_found(key),
+            Some(value) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&value).unwrap_or_else(|e| fail(e));
+                stdout.write_all(b"\n").unwrap_or_else(|e| fail(e));
+            }
+        },
+        "delete" => {
+            if !store.contains_key(key.as_bytes()).unwrap_or_else(|e| fail(e)) {
+                not_found(key);
+            }
+            store.delete(key.as_bytes()).unwrap_or_else(|e| fail(e));
+        }
+        "insert" => {
+            let value = maybe_value.unwrap_or_else(|| usage());
+            store.insert(key.as_bytes(), value.as_bytes()).unwrap_or_else(|e| fail(e));
+        }
+        "update" => {
+            let value = maybe_value.unwrap_or_else(|| usage());
+            if !store.contains_key(key.as_bytes()).unwrap_or_else(|e| fail(e)) {
+                not_found(key);
+            }
+            store.update(key.as_bytes(), value.as_bytes()).unwrap_or_else(|e| fail(e));
+        }
+        _ => usage(),
+    }
+}
 
-}
\ No newline at end of file
+/// `akv_disk FILE hint`: saves the index so that later calls load it
+/// instead of scanning the whole log. Each call only scans what was
+/// written since, so there is no need to run it after every write.
+fn write_hint(path: &Path) {
+    let mut store = Options::new().repair_torn_tail(true).open(path).unwrap_or_else(|e| fail(e));
+    store.load().unwrap_or_else(|e| fail(e));
+    store.write_hint().unwrap_or_else(|e| fail(e));
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+use libactionkv::{exit, ActionKvError, Options, RecordStatus};
+
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_fsck.exe FILE [--quiet] [--repair OUT] [--key-file KEY]
+
+Lists every record in FILE, then live and dead records per key and a
+summary. --quiet prints the summary only. --repair writes a compacted copy
+of FILE to OUT, leaving out damaged records. --key-file reads the 32-byte
+encryption key from KEY; without it, encrypted records can't be checked
+for tampering or repaired.
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_fsck FILE [--quiet] [--repair OUT] [--key-file KEY]
+
+Lists every record in FILE, then live and dead records per key and a
+summary. --quiet prints the summary only. --repair writes a compacted copy
+of FILE to OUT, leaving out damaged records. --key-file reads the 32-byte
+encryption key from KEY; without it, encrypted records can't be checked
+for tampering or repaired.
+";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(exit::USAGE);
+}
+
+fn fail<E: Into<ActionKvError>>(err: E) -> ! {
+    let err = err.into();
+    eprintln!("error: {}", err);
+    process::exit(err.exit_code());
+}
+
+#[cfg(feature = "encryption")]
+fn set_key(options: &mut Options, path: &Path) {
+    let bytes = std::fs::read(path).unwrap_or_else(|e| fail(e));
+    let key: [u8; 32] = bytes.try_into().unwrap_or_else(|_| {
+        eprintln!("error: {} must hold exactly 32 bytes", path.display());
+        process::exit(exit::USAGE);
+    });
+    options.encryption_key(libactionkv::EncryptionKey::new(&key));
+}
+
+#[cfg(not(feature = "encryption"))]
+fn set_key(_options: &mut Options, _path: &Path) {
+    eprintln!("error: akv_fsck was built without the encryption feature");
+    process::exit(exit::USAGE);
+}
+
+#[derive(Default)]
+struct Totals {
+    records: u64,
+    live: u64,
+    dead: u64,
+    damaged: u64,
+    torn: u64,
+    bytes: u64,
+    live_bytes: u64,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).unwrap_or_else(|| usage());
+    let mut quiet = false;
+    let mut repair = None;
+    let mut options = Options::new();
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--quiet" => quiet = true,
+            "--repair" => repair = Some(rest.next().unwrap_or_else(|| usage())),
+            "--key-file" => set_key(&mut options, Path::new(rest.next().unwrap_or_else(|| usage()))),
+            _ => usage(),
+        }
+    }
+
+    let check = options.check(Path::new(fname)).unwrap_or_else(|e| fail(e));
+    if check.needs_key() {
+        eprintln!("warning: {} has encrypted records; without --key-file they are counted as dead", fname);
+    }
+    let live_keys = check.live();
+    if let Some(out) = repair {
+        check.repair_to(Path::new(out)).unwrap_or_else(|e| fail(e));
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    if !quiet {
+        writeln!(out, "segment\toffset\tkey_len\tvalue_len\tflags\tcrc\tstate").unwrap_or_else(|e| fail(e));
+    }
+
+    let mut totals = Totals::default();
+    // key -> (live, dead)
+    let mut per_key: BTreeMap<Vec<u8>, (u64, u64)> = BTreeMap::new();
+    for rec in check {
+        let rec = rec.unwrap_or_else(|e| fail(e));
+        totals.records += 1;
+        totals.bytes += rec.len;
+        let crc = match rec.status {
+            RecordStatus::Ok => "ok".to_string(),
+            RecordStatus::BadChecksum { expected, actual } => {
+                totals.damaged += 1;
+                format!("bad ({:08x} != {:08x})", actual, expected)
+            }
+            RecordStatus::Tampered => {
+                totals.damaged += 1;
+                "tampered".to_string()
+            }
+            RecordStatus::Torn => {
+                totals.torn += rec.len;
+                "torn".to_string()
+            }
+        };
+        if rec.live {
+            totals.live += 1;
+            totals.live_bytes += rec.len;
+        } else if rec.status == RecordStatus::Ok {
+            totals.dead += 1;
+        }
+        if let Some(key) = rec.key {
+            let counts = per_key.entry(key).or_default();
+            if rec.live {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+
+        if !quiet {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{:#04x}\t{}\t{}",
+                rec.position.segment,
+                rec.position.offset,
+                rec.key_len,
+                rec.value_len,
+                rec.flags,
+                crc,
+                if rec.live { "live" } else { "dead" },
+            ).unwrap_or_else(|e| fail(e));
+        }
+    }
+
+    if !quiet {
+        writeln!(out, "\nkey\tlive\tdead").unwrap_or_else(|e| fail(e));
+        for (key, (live, dead)) in &per_key {
+            writeln!(out, "{:?}\t{}\t{}", String::from_utf8_lossy(key), live, dead).unwrap_or_else(|e| fail(e));
+        }
+        writeln!(out).unwrap_or_else(|e| fail(e));
+    }
+
+    // 压缩后只剩下活记录，其余的字节都能收回（不算文件头）
+    let reclaim = totals.bytes - totals.live_bytes;
+    writeln!(
+        out,
+        "{} records: {} live, {} dead, {} damaged\n\
+         {} torn bytes at segment ends\n\
+         compaction would reclaim about {} of {} bytes",
+        totals.records,
+        totals.live,
+        totals.dead,
+        totals.damaged,
+        totals.torn,
+        reclaim,
+        totals.bytes,
+    ).unwrap_or_else(|e| fail(e));
+    if let Some(out_path) = repair {
+        writeln!(out, "wrote {} live keys to {}", live_keys, out_path).unwrap_or_else(|e| fail(e));
+    }
+    out.flush().unwrap_or_else(|e| fail(e));
+
+    // 退出码：日志完好是 0，有坏记录是 exit::CORRUPT
+    if totals.damaged > 0 || totals.torn > 0 {
+        process::exit(exit::CORRUPT);
+    }
+}
@@ -1,8 +1,35 @@
-use byteorder::LittleEndian;
+use std::io;
+use std::io::prelude::*;
+use std::ops::Bound;
+use std::path::Path;
+use std::process;
 
-use libactionkv::ActionKV;
+use libactionkv::{exit, ActionKV, ActionKvError, KvEngine, MemKV, Options, Result};
 
 
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_mem.exe FILE get KEY
+    akv_mem.exe FILE delete KEY
+    akv_mem.exe FILE insert KEY VALUE
+    akv_mem.exe FILE update KEY VALUE
+    akv_mem.exe --session [FILE]
+
+--session reads commands from stdin, one per line, and runs them against a
+store held in memory only:
+
+    get KEY
+    insert KEY VALUE
+    update KEY VALUE
+    delete KEY
+    scan [START [END]]
+
+The session starts out with FILE's live pairs if FILE is given. FILE is
+opened read-only and never written. It exits with 1 at the end if a key was
+missing, and stops with 2 at a line that isn't a command.
+";
+
 #[cfg(not(target_os = "windows"))]
 const USAGE: &str = "
 Usage:
@@ -10,10 +37,166 @@ Usage:
     akv_mem FILE delete KEY
     akv_mem FILE insert KEY VALUE
     akv_mem FILE update KEY VALUE
+    akv_mem --session [FILE]
+
+--session reads commands from stdin, one per line, and runs them against a
+store held in memory only:
+
+    get KEY
+    insert KEY VALUE
+    update KEY VALUE
+    delete KEY
+    scan [START [END]]
+
+The session starts out with FILE's live pairs if FILE is given. FILE is
+opened read-only and never written. It exits with 1 at the end if a key was
+missing, and stops with 2 at a line that isn't a command.
 ";
 
-fn main(){
-    let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(exit::USAGE);
+}
+
+fn fail<E: Into<ActionKvError>>(err: E) -> ! {
+    let err = err.into();
+    eprintln!("error: {}", err);
+    process::exit(err.exit_code());
+}
+
+enum Outcome {
+    Done,
+    /// The key the command names is missing. Already said so on stderr.
+    NotFound,
+    /// No such command, or the wrong arguments for it.
+    BadCommand,
+}
+
+/// Runs one command against `store`, whichever engine it is.
+fn run<E: KvEngine>(
+    store: &mut E,
+    action: &str,
+    key: Option<&str>,
+    value: Option<&str>,
+    out: &mut impl Write,
+) -> Result<Outcome> {
+    let not_found = |key: &str| {
+        eprintln!("{:?} not found", key);
+        Ok(Outcome::NotFound)
+    };
+
+    match (action, key, value) {
+        ("get", Some(key), None) => match store.get(key.as_bytes())? {
+            None => return not_found(key),
+            Some(value) => {
+                out.write_all(&value)?;
+                out.write_all(b"\n")?;
+            }
+        },
+        ("delete", Some(key), None) => {
+            if !store.contains_key(key.as_bytes())? {
+                return not_found(key);
+            }
+            store.delete(key.as_bytes())?;
+        }
+        ("insert", Some(key), Some(value)) => store.put(key.as_bytes(), value.as_bytes())?,
+        ("update", Some(key), Some(value)) => {
+            if !store.contains_key(key.as_bytes())? {
+                return not_found(key);
+            }
+            store.put(key.as_bytes(), value.as_bytes())?;
+        }
+        ("scan", start, end) => {
+            let start = start.map_or(Bound::Unbounded, |k| Bound::Included(k.as_bytes()));
+            let end = end.map_or(Bound::Unbounded, |k| Bound::Excluded(k.as_bytes()));
+            for kv in store.scan((start, end)) {
+                let kv = kv?;
+                out.write_all(&kv.key)?;
+                out.write_all(b"\t")?;
+                out.write_all(&kv.value)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        _ => return Ok(Outcome::BadCommand),
+    }
+    Ok(Outcome::Done)
+}
+
+/// `akv_mem FILE ACTION KEY [VALUE]`: one command, on the store itself.
+fn run_once(args: &[String]) {
+    let fname = args.get(1).unwrap_or_else(|| usage());
+    let action = args.get(2).unwrap_or_else(|| usage()).as_str();
+    let key = args.get(3).unwrap_or_else(|| usage());
+    let maybe_value = args.get(4).map(String::as_str);
+    if !matches!(action, "get" | "delete" | "insert" | "update") || args.len() > 5 {
+        usage();
+    }
+
+    let path = Path::new(fname);
+    // 只读的话不用等写者放锁；写的话先切掉上次崩溃留下的半条记录
+    let mut store: ActionKV = match Options::new().read_only(action == "get").repair_torn_tail(true).open(path) {
+        Ok(store) => store,
+        Err(ActionKvError::Io(err)) if action == "get" && err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{:?} not found", key);
+            process::exit(exit::NOT_FOUND);
+        }
+        Err(err) => fail(err),
+    };
+    store.load().unwrap_or_else(|e| fail(e));
+
+    let mut stdout = io::stdout();
+    match run(&mut store, action, Some(key), maybe_value, &mut stdout).unwrap_or_else(|e| fail(e)) {
+        Outcome::Done => {}
+        Outcome::NotFound => process::exit(exit::NOT_FOUND),
+        Outcome::BadCommand => usage(),
+    }
 }
 
+/// `akv_mem --session [FILE]`: commands from stdin, on a copy in memory.
+fn run_session(fname: Option<&String>) {
+    let mut store = match fname {
+        None => MemKV::new(),
+        Some(fname) => {
+            let mut file = Options::new().read_only(true).open(Path::new(fname)).unwrap_or_else(|e| fail(e));
+            file.load().unwrap_or_else(|e| fail(e));
+            MemKV::copy_of(&file).unwrap_or_else(|e| fail(e))
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut missed = false;
+    for line in io::stdin().lock().lines() {
+        let line = line.unwrap_or_else(|e| fail(e));
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        // VALUE 是行的剩余部分，可以带空格
+        let mut words = line.splitn(3, ' ');
+        let action = words.next().unwrap_or_default();
+        match run(&mut store, action, words.next(), words.next(), &mut out).unwrap_or_else(|e| fail(e)) {
+            Outcome::Done => {}
+            Outcome::NotFound => missed = true,
+            Outcome::BadCommand => {
+                out.flush().unwrap_or_else(|e| fail(e));
+                eprintln!("not a command: {:?}", line);
+                process::exit(exit::USAGE);
+            }
+        }
+    }
+    out.flush().unwrap_or_else(|e| fail(e));
+
+    if missed {
+        process::exit(exit::NOT_FOUND);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("--session") if args.len() <= 3 => run_session(args.get(2)),
+        Some("--session") | None => usage(),
+        Some(_) => run_once(&args),
+    }
+}
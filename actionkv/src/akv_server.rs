@@ -0,0 +1,97 @@
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use libactionkv::{exit, ActionKvError, Follower, Leader, Options, Server, SharedKV};
+
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_server.exe FILE [ADDR] [--leader REPL_ADDR]
+    akv_server.exe FILE [ADDR] --follow LEADER_ADDR
+
+ADDR defaults to 127.0.0.1:7379. --leader also serves the log to followers
+on REPL_ADDR; --follow copies a leader's log into FILE and serves reads
+only, answering writes with -READONLY.
+
+The replication stream is plaintext with no authentication: anyone who can
+reach REPL_ADDR can read the whole store. Keep it on loopback or a private
+network, and tunnel it to followers elsewhere.
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_server FILE [ADDR] [--leader REPL_ADDR]
+    akv_server FILE [ADDR] --follow LEADER_ADDR
+
+ADDR defaults to 127.0.0.1:7379. --leader also serves the log to followers
+on REPL_ADDR; --follow copies a leader's log into FILE and serves reads
+only, answering writes with -READONLY.
+
+The replication stream is plaintext with no authentication: anyone who can
+reach REPL_ADDR can read the whole store. Keep it on loopback or a private
+network, and tunnel it to followers elsewhere.
+";
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7379";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(exit::USAGE);
+}
+
+fn fail<E: Into<ActionKvError>>(err: E) -> ! {
+    let err = err.into();
+    eprintln!("error: {}", err);
+    process::exit(err.exit_code());
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).unwrap_or_else(|| usage());
+    let mut rest = &args[2..];
+    let addr = match rest.first() {
+        Some(addr) if !addr.starts_with("--") => {
+            rest = &rest[1..];
+            addr.as_str()
+        }
+        _ => DEFAULT_ADDR,
+    };
+    let replication = match rest {
+        [] => None,
+        [flag, other] if flag == "--leader" || flag == "--follow" => Some((flag.as_str(), other.as_str())),
+        _ => usage(),
+    };
+
+    // 上次崩溃留下的半条记录要先切掉，不然后面的写入下次启动就读不到了
+    let store = SharedKV::open_with(Path::new(fname), Options::new().repair_torn_tail(true)).unwrap_or_else(|e| fail(e));
+    match replication {
+        Some(("--leader", repl_addr)) => {
+            let leader = Leader::bind(repl_addr, store.clone()).unwrap_or_else(|e| fail(e));
+            eprintln!("replicating {} on {}", fname, leader.local_addr().unwrap_or_else(|e| fail(e)));
+            thread::spawn(move || leader.run().unwrap_or_else(|e| fail(e)));
+        }
+        Some((_, leader_addr)) => {
+            let mut follower = Follower::new(store.clone()).unwrap_or_else(|e| fail(e));
+            let leader_addr = leader_addr.to_string();
+            thread::spawn(move || loop {
+                // leader 暂时连不上就过一会儿再试
+                if let Err(err) = follower.follow(leader_addr.as_str()) {
+                    eprintln!("following {}: {}", leader_addr, err);
+                }
+                thread::sleep(Duration::from_secs(1));
+            });
+        }
+        None => {}
+    }
+
+    let mut server = Server::bind(addr, store).unwrap_or_else(|e| fail(e));
+    // follower 自己写的会被 leader 的日志盖掉，干脆不让写
+    server.read_only(matches!(replication, Some(("--follow", _))));
+    eprintln!("serving {} on {}", fname, server.local_addr().unwrap_or_else(|e| fail(e)));
+
+    server.run().unwrap_or_else(|e| fail(e));
+}
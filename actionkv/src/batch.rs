@@ -0,0 +1,148 @@
+//! Atomic write batches.
+//!
+//! A batch goes to the log in one write, framed like this:
+//!
+//! BATCH_BEGIN | op | op | ... | BATCH_COMMIT
+//!
+//! Every part is an ordinary record in the active segment's format (v2 or
+//! later). The begin marker's value is the op count (u32). Each op is a put
+//! or tombstone flagged `BATCHED`. The commit marker's value is the op
+//! count again followed by the batch checksum (u32): a crc32 over the ops'
+//! own checksums, in order. A batch whose commit marker never made it to
+//! disk is ignored when the log is read.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32::{self, Hasher32};
+
+use crate::codec::Codec;
+use crate::error::Result;
+use crate::record::{self, Version, FLAG_BATCHED, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT, FLAG_TOMBSTONE};
+use crate::{ByteStr, ByteString};
+
+#[derive(Debug, Clone)]
+enum BatchOp {
+    Insert(ByteString, ByteString),
+    Delete(ByteString),
+}
+
+/// Inserts and deletes that reach the log together or not at all. Build
+/// one up, then hand it to `ActionKV::write_batch`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> &mut Self {
+        self.ops.push(BatchOp::Insert(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// Each op's key, and its value if it is an insert.
+    pub(crate) fn ops(&self) -> impl Iterator<Item = (&ByteStr, Option<&ByteStr>)> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Insert(key, value) => (key.as_slice(), Some(value.as_slice())),
+            BatchOp::Delete(key) => (key.as_slice(), None),
+        })
+    }
+}
+
+/// Where each op landed, relative to the start of the encoded batch.
+pub(crate) struct EncodedOp {
+    pub key: ByteString,
+    pub offset: u64,
+    pub tombstone: bool,
+}
+
+pub(crate) fn encode(version: Version, codec: &Codec, batch: &WriteBatch) -> Result<(ByteString, Vec<EncodedOp>)> {
+    let count = batch.ops.len() as u32;
+    let mut count_bytes = ByteString::new();
+    count_bytes.write_u32::<LittleEndian>(count)?;
+
+    let mut buf = record::encode(version, b"", &count_bytes, FLAG_BATCH_BEGIN)?;
+    let mut ops = Vec::with_capacity(batch.ops.len());
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+
+    for op in &batch.ops {
+        let (key, value, flags) = match op {
+            BatchOp::Insert(key, value) => (key, value.as_slice(), FLAG_BATCHED),
+            BatchOp::Delete(key) => (key, &b""[..], FLAG_BATCHED | FLAG_TOMBSTONE),
+        };
+        let rec = codec.encode(version, key, value, flags, None)?;
+        // 记录的前 4 个字节就是它自己的 checksum
+        digest.write(&rec[..4]);
+        ops.push(EncodedOp {
+            key: key.clone(),
+            offset: buf.len() as u64,
+            tombstone: flags & FLAG_TOMBSTONE != 0,
+        });
+        buf.extend_from_slice(&rec);
+    }
+
+    let mut commit = count_bytes;
+    commit.write_u32::<LittleEndian>(digest.sum32())?;
+    buf.extend(record::encode(version, b"", &commit, FLAG_BATCH_COMMIT)?);
+
+    Ok((buf, ops))
+}
+
+/// Collects a batch's ops while the log is read, until its commit marker
+/// shows up.
+pub(crate) struct PendingBatch<T> {
+    pub begin_offset: u64,
+    expected: u32,
+    digest: crc32::Digest,
+    pub ops: Vec<T>,
+}
+
+impl<T> PendingBatch<T> {
+    pub fn begin(begin_offset: u64, value: &ByteStr) -> Self {
+        let mut value = value;
+        let expected = value.read_u32::<LittleEndian>().unwrap_or(u32::MAX);
+        PendingBatch {
+            begin_offset,
+            expected,
+            digest: crc32::Digest::new(crc32::IEEE),
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, checksum: u32, op: T) {
+        self.digest.write(&checksum.to_le_bytes());
+        self.ops.push(op);
+    }
+
+    /// Checks the commit marker's value against what was collected. Returns
+    /// the (expected, actual) batch checksums on a mismatch.
+    pub fn verify(&self, commit: &ByteStr) -> std::result::Result<(), (u32, u32)> {
+        let mut commit = commit;
+        let count = commit.read_u32::<LittleEndian>().unwrap_or(u32::MAX);
+        let saved = commit.read_u32::<LittleEndian>().unwrap_or(0);
+        let actual = self.digest.sum32();
+        if count != self.expected || count as usize != self.ops.len() || saved != actual {
+            return Err((saved, actual));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Checking a log record by record, without opening it as a store. See
+//! `Options::check` and `akv_fsck`.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::codec::Codec;
+use crate::crypto;
+use crate::error::{ActionKvError, Result};
+use crate::index::Index;
+use crate::record::{self, Version, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT, FLAG_ENCRYPTED};
+use crate::segment::{self, Position, Segment};
+use crate::walk::walk_past_damage;
+use crate::{ByteString, Snapshot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordStatus {
+    Ok,
+    /// The bytes don't match the record's CRC. Its lengths may be wrong
+    /// too, in which case what follows is misread until the next torn
+    /// tail.
+    BadChecksum { expected: u32, actual: u32 },
+    /// The CRC matches but the record fails authentication. See `crypto`.
+    Tampered,
+    /// The segment ends partway through the record.
+    Torn,
+}
+
+#[derive(Debug)]
+pub struct RecordCheck {
+    pub position: Position,
+    /// Bytes the record takes up. For a torn record, what is left of the
+    /// segment.
+    pub len: u64,
+    pub key_len: u32,
+    pub value_len: u32,
+    pub flags: u8,
+    pub status: RecordStatus,
+    /// `None` for damaged records and batch markers, and for encrypted
+    /// records when no key was given.
+    pub key: Option<ByteString>,
+    /// Whether loading the log (skipping damaged records) ends up with
+    /// this record in the index.
+    pub live: bool,
+}
+
+/// Every record in a log, in order, as `RecordCheck`s.
+#[derive(Debug)]
+pub struct LogCheck {
+    segments: Vec<Arc<Segment>>,
+    codec: Codec,
+    index: Index,
+    end: Position,
+    /// The first encrypted record that couldn't be read for lack of a key,
+    /// if any. Its key, and whether it is live, is unknown.
+    missing_key: Option<u64>,
+    next_segment: usize,
+    current: Option<(u32, BufReader<File>, Version, u64)>,
+}
+
+impl LogCheck {
+    pub(crate) fn new(path: &Path, codec: Codec) -> Result<Self> {
+        let ids = segment::list_segments(path)?;
+        if ids.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no log at {}", path.display()),
+            ).into());
+        }
+        let segments: Vec<Arc<Segment>> = ids.iter().map(|id| Arc::new(Segment::new(path, *id))).collect();
+
+        // 和 load 一样建索引，只是跳过坏记录
+        let mut index = Index::new(true);
+        let mut end = Position::default();
+        let mut missing_key = None;
+        let now = record::now_millis();
+        for segment in &segments {
+            let (f, version) = segment.reader()?;
+            let id = segment.id;
+            // 认证失败、没有 key 的记录也当坏记录跳过去
+            walk_past_damage(
+                f,
+                version,
+                version.data_start(),
+                &codec,
+                |offset, rec, _| {
+                    if rec.is_tombstone() || rec.is_expired(now) {
+                        index.remove(&rec.kv.key);
+                    } else {
+                        index.insert(rec.kv.key, Position::new(id, offset));
+                    }
+                    Ok(())
+                },
+                |err| {
+                    if let ActionKvError::NoEncryptionKey { offset } = err {
+                        missing_key.get_or_insert(*offset);
+                    }
+                },
+            )?;
+            end = Position::new(id, f.metadata()?.len());
+        }
+
+        Ok(LogCheck { segments, codec, index, end, missing_key, next_segment: 0, current: None })
+    }
+
+    /// How many keys are live. Encrypted records checked without a key
+    /// aren't counted.
+    pub fn live(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the log has encrypted records that the check couldn't read
+    /// because it was made without a key.
+    pub fn needs_key(&self) -> bool {
+        self.missing_key.is_some()
+    }
+
+    /// Writes the live pairs to a new store at `path`, leaving out damaged
+    /// records. See `Snapshot::export_to`. Fails with
+    /// `ActionKvError::NoEncryptionKey` if the log has encrypted records
+    /// and the check was made without a key, rather than leave them out.
+    pub fn repair_to(&self, path: &Path) -> Result<()> {
+        if let Some(offset) = self.missing_key {
+            return Err(ActionKvError::NoEncryptionKey { offset });
+        }
+        let snapshot = Snapshot::new(self.end, self.segments.clone(), self.codec.clone(), self.index.clone())?;
+        snapshot.export_to(path)
+    }
+
+    fn open_next(&mut self) -> Result<bool> {
+        let segment = match self.segments.get(self.next_segment) {
+            Some(segment) => segment,
+            None => return Ok(false),
+        };
+        self.next_segment += 1;
+
+        let mut f = BufReader::new(File::open(&segment.path)?);
+        let version = record::read_file_header(&mut f)?;
+        let len = f.get_ref().metadata()?.len();
+        f.seek(SeekFrom::Start(version.data_start()))?;
+        self.current = Some((segment.id, f, version, len));
+        Ok(true)
+    }
+
+    fn check_next(&mut self) -> Result<Option<RecordCheck>> {
+        loop {
+            if self.current.is_none() && !self.open_next()? {
+                return Ok(None);
+            }
+            let (id, f, version, len) = self.current.as_mut().unwrap();
+            let (id, version, len) = (*id, *version, *len);
+            let offset = f.stream_position()?;
+            if offset >= len {
+                self.current = None;
+                continue;
+            }
+            let position = Position::new(id, offset);
+
+            let raw = match record::read_raw(f, version) {
+                Ok(raw) => raw,
+                Err(err) if err.is_eof() => {
+                    self.current = None;
+                    return Ok(Some(RecordCheck {
+                        position,
+                        len: len - offset,
+                        key_len: 0,
+                        value_len: 0,
+                        flags: 0,
+                        status: RecordStatus::Torn,
+                        key: None,
+                        live: false,
+                    }));
+                }
+                Err(err) => return Err(err),
+            };
+
+            let flags = raw.flags(version);
+            let actual = raw.actual_checksum();
+            let (status, key) = if actual != raw.checksum {
+                (RecordStatus::BadChecksum { expected: raw.checksum, actual }, None)
+            } else if flags & (FLAG_BATCH_BEGIN | FLAG_BATCH_COMMIT) != 0 {
+                (RecordStatus::Ok, None)
+            } else if flags & FLAG_ENCRYPTED != 0 {
+                let expires_at = raw.expires_at(version);
+                match crypto::open(self.codec.key.as_ref(), flags, expires_at, raw.value(version), offset) {
+                    Ok((key, _)) => (RecordStatus::Ok, Some(key)),
+                    Err(ActionKvError::Tampered { .. }) => (RecordStatus::Tampered, None),
+                    Err(_) => (RecordStatus::Ok, None),
+                }
+            } else {
+                (RecordStatus::Ok, Some(raw.key(version).to_vec()))
+            };
+            let live = key.as_ref().is_some_and(|key| self.index.get(key) == Some(&position));
+
+            return Ok(Some(RecordCheck {
+                position,
+                len: raw.len(),
+                key_len: raw.key_len,
+                value_len: raw.value_len,
+                flags,
+                status,
+                key,
+                live,
+            }));
+        }
+    }
+}
+
+impl Iterator for LogCheck {
+    type Item = Result<RecordCheck>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.check_next().transpose()
+    }
+}
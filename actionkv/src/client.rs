@@ -0,0 +1,93 @@
+//! A client for `akv_server`. Any RESP client (`redis-cli`, say) works
+//! too; this one just saves you the framing.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::error::Result;
+use crate::resp::{self, Value};
+use crate::{ByteStr, ByteString, KeyValuePair};
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+fn unexpected(reply: Value) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply: {:?}", reply))
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends one command and waits for the reply. An error reply from the
+    /// server comes back as an `io::Error` carrying its message.
+    fn call(&mut self, args: &[&ByteStr]) -> Result<Value> {
+        resp::write_value(&mut self.writer, &resp::command(args))?;
+        self.writer.flush()?;
+
+        match resp::read_value(&mut self.reader)? {
+            Some(Value::Error(msg)) => Err(io::Error::other(msg).into()),
+            Some(reply) => Ok(reply),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(&[b"PING"])? {
+            Value::Simple(_) => Ok(()),
+            reply => Err(unexpected(reply).into()),
+        }
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        match self.call(&[b"GET", key])? {
+            Value::Bulk(value) => Ok(value),
+            reply => Err(unexpected(reply).into()),
+        }
+    }
+
+    pub fn set(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        match self.call(&[b"SET", key, value])? {
+            Value::Simple(_) => Ok(()),
+            reply => Err(unexpected(reply).into()),
+        }
+    }
+
+    /// Deletes `key`. Returns whether it was there.
+    pub fn del(&mut self, key: &ByteStr) -> Result<bool> {
+        match self.call(&[b"DEL", key])? {
+            Value::Integer(n) => Ok(n > 0),
+            reply => Err(unexpected(reply).into()),
+        }
+    }
+
+    /// Every pair whose key starts with `prefix`, in key order.
+    pub fn scan(&mut self, prefix: &ByteStr) -> Result<Vec<KeyValuePair>> {
+        let items = match self.call(&[b"SCAN", prefix])? {
+            Value::Array(items) if items.len() % 2 == 0 => items,
+            reply => return Err(unexpected(reply).into()),
+        };
+
+        let mut pairs = Vec::with_capacity(items.len() / 2);
+        let mut items = items.into_iter();
+        while let (Some(key), Some(value)) = (items.next(), items.next()) {
+            match (key, value) {
+                (Value::Bulk(Some(key)), Value::Bulk(Some(value))) => {
+                    pairs.push(KeyValuePair { key, value })
+                }
+                (key, _) => return Err(unexpected(key).into()),
+            }
+        }
+        Ok(pairs)
+    }
+}
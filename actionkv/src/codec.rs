@@ -0,0 +1,112 @@
+//! How records are turned into bytes beyond the basic layout in `record`:
+//! value compression here, encryption in `crypto`.
+//!
+//! A compressed record says so with a codec flag next to `FLAG_TOMBSTONE`,
+//! so compressed and plain records can share a segment and the setting can
+//! change between runs. The checksum covers the bytes as stored. Codecs
+//! other than `Compression::None` need the `compression` cargo feature;
+//! without it, reading a compressed record is an
+//! `ActionKvError::UnsupportedCodec` error.
+
+use std::borrow::Cow;
+#[cfg(feature = "compression")]
+use std::io;
+
+use crate::crypto::EncryptionKey;
+use crate::error::{ActionKvError, Result};
+#[cfg(feature = "compression")]
+use crate::record::FLAG_LZ4;
+#[cfg(feature = "encryption")]
+use crate::record::FLAG_ENCRYPTED;
+use crate::record::{self, Version, FLAG_CODECS};
+use crate::{ByteStr, ByteString};
+
+/// How values are compressed when they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format, with the uncompressed length in front.
+    #[cfg(feature = "compression")]
+    Lz4,
+}
+
+/// The settings from `Options` that change what records look like on disk.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Codec {
+    pub compression: Compression,
+    pub key: Option<EncryptionKey>,
+}
+
+impl Codec {
+    /// `record::encode_expiring`, compressing and encrypting on the way.
+    pub fn encode(
+        &self,
+        version: Version,
+        key: &ByteStr,
+        value: &ByteStr,
+        flags: u8,
+        expires_at: Option<u64>,
+    ) -> Result<ByteString> {
+        let (value, codec_flag) = compress(self.compression, version, value);
+        let flags = flags | codec_flag;
+
+        #[cfg(feature = "encryption")]
+        if let Some(secret) = &self.key {
+            if version < Version::V2 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "v1 segments can't hold encrypted records",
+                ).into());
+            }
+            let flags = flags | FLAG_ENCRYPTED;
+            let sealed = secret.seal(flags, expires_at, key, &value)?;
+            return record::encode_expiring(version, b"", &sealed, flags, expires_at);
+        }
+
+        record::encode_expiring(version, key, &value, flags, expires_at)
+    }
+
+    /// Whether records need a flags byte, i.e. at least a v2 segment.
+    pub fn needs_flags(&self) -> bool {
+        self.key.is_some()
+    }
+}
+
+/// The bytes to store for `value` and the codec flag to set on its record.
+/// Values that don't get smaller are stored as they are, and so is
+/// everything in a v1 segment, which has nowhere to put the flag.
+pub(crate) fn compress(codec: Compression, version: Version, value: &ByteStr) -> (Cow<'_, ByteStr>, u8) {
+    if version < Version::V2 {
+        return (Cow::Borrowed(value), 0);
+    }
+
+    match codec {
+        Compression::None => (Cow::Borrowed(value), 0),
+        #[cfg(feature = "compression")]
+        Compression::Lz4 => {
+            let compressed = lz4_flex::compress_prepend_size(value);
+            if compressed.len() < value.len() {
+                (Cow::Owned(compressed), FLAG_LZ4)
+            } else {
+                (Cow::Borrowed(value), 0)
+            }
+        }
+    }
+}
+
+/// Undoes `compress` for the record at `offset`, going by its flags.
+pub(crate) fn decompress(flags: u8, value: ByteString, offset: u64) -> Result<ByteString> {
+    match flags & FLAG_CODECS {
+        0 => Ok(value),
+        #[cfg(feature = "compression")]
+        FLAG_LZ4 => lz4_flex::decompress_size_prepended(&value).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad lz4 value at offset {}: {}", offset, err),
+            )
+            .into()
+        }),
+        _ => Err(ActionKvError::UnsupportedCodec { offset, flags }),
+    }
+}
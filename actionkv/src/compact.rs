@@ -0,0 +1,258 @@
+//! Log compaction.
+//!
+//! Compaction runs in three steps so that the expensive part does not need
+//! the store at all:
+//!
+//! 1. `ActionKV::start_compaction` takes a copy of the index and notes where
+//!    the log ends. This is cheap.
+//! 2. `Compaction::run` copies every live, unexpired record into
+//!    `FILE.compact` using its own file handles. The store keeps serving
+//!    reads and writes while this happens.
+//! 3. `ActionKV::finish_compaction` copies whatever was appended since step
+//!    1, then swaps the new file in as a single segment and takes over its
+//!    index.
+//!
+//! `ActionKV::compact` does all three in one go. A store runs one
+//! compaction at a time, since they all write to the same `FILE.compact`.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::codec::Codec;
+use crate::error::Result;
+use crate::index::Index;
+use crate::options::RecoveryPolicy;
+use crate::record::{self, Version, FLAG_TOMBSTONE};
+use crate::walk::walk_segment;
+use crate::segment::{segment_path, Position};
+use crate::ByteString;
+
+pub(crate) fn compact_path(base: &Path) -> PathBuf {
+    let mut name: OsString = base.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+/// Set while a store has a compaction in progress. There is only one
+/// `FILE.compact`, so two compactions at once would write over each other.
+#[derive(Debug, Default)]
+pub(crate) struct Running(Arc<AtomicBool>);
+
+impl Running {
+    /// Marks a compaction as started, unless one already is.
+    pub(crate) fn start(&self, base: &Path) -> io::Result<RunningGuard> {
+        if self.0.swap(true, Ordering::AcqRel) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("a compaction of {} is already running", base.display()),
+            ));
+        }
+        Ok(RunningGuard(self.0.clone()))
+    }
+
+    pub(crate) fn owns(&self, guard: &RunningGuard) -> bool {
+        Arc::ptr_eq(&self.0, &guard.0)
+    }
+}
+
+/// Clears the flag when the compaction is finished or dropped.
+#[derive(Debug)]
+pub(crate) struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// A compaction in progress. See the module docs for how it is driven.
+#[derive(Debug)]
+pub struct Compaction {
+    pub(crate) base: PathBuf,
+    pub(crate) running: RunningGuard,
+    /// Where the log ended when the compaction started; records past it are
+    /// copied by `finish_compaction`.
+    pub(crate) started_at: Position,
+    live: Vec<(ByteString, Position)>,
+    out: Option<BufWriter<File>>,
+    /// The new file once `catch_up` has synced it, to check that it is
+    /// still the one at `FILE.compact` when it is swapped in.
+    done: Option<File>,
+    out_len: u64,
+    index: HashMap<ByteString, u64>,
+    codec: Codec,
+}
+
+impl Compaction {
+    pub(crate) fn new(
+        base: &Path,
+        running: RunningGuard,
+        started_at: Position,
+        index: &Index,
+        codec: Codec,
+    ) -> Self {
+        let mut live: Vec<(ByteString, Position)> = index
+            .iter()
+            .map(|(key, position)| (key.clone(), *position))
+            .collect();
+        // 按位置排序，读旧文件时尽量顺序读
+        live.sort_by_key(|(_, position)| *position);
+
+        Compaction {
+            base: base.to_path_buf(),
+            running,
+            started_at,
+            live,
+            out: None,
+            done: None,
+            out_len: 0,
+            index: HashMap::new(),
+            codec,
+        }
+    }
+
+    /// Copies the live records into the new file. Does not touch the store.
+    pub fn run(&mut self) -> Result<()> {
+        let mut out = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(compact_path(&self.base))?,
+        );
+        record::write_file_header(&mut out, Version::CURRENT)?;
+        self.out_len = Version::CURRENT.data_start();
+
+        let now = record::now_millis();
+        let mut source: Option<(u32, BufReader<File>, Version)> = None;
+        for (key, position) in std::mem::take(&mut self.live) {
+            if source.as_ref().map(|(id, _, _)| *id) != Some(position.segment) {
+                let mut f = File::open(segment_path(&self.base, position.segment))?;
+                let version = record::read_file_header(&mut f)?;
+                source = Some((position.segment, BufReader::new(f), version));
+            }
+            let (_, f, version) = source.as_mut().unwrap();
+
+            f.seek(SeekFrom::Start(position.offset))?;
+            let rec = record::decode(f, *version, position.offset, &self.codec)?;
+            if rec.is_expired(now) {
+                continue;
+            }
+            let buf = self.codec.encode(Version::CURRENT, &key, &rec.kv.value, 0, rec.expires_at)?;
+            out.write_all(&buf)?;
+            self.index.insert(key, self.out_len);
+            self.out_len += buf.len() as u64;
+        }
+
+        self.out = Some(out);
+        Ok(())
+    }
+
+    /// Copies records written after `started_at` in any of `segments` and
+    /// makes the new file durable. Returns the new index as offsets into it.
+    pub(crate) fn catch_up(&mut self, segments: &[u32]) -> Result<HashMap<ByteString, u64>> {
+        if self.out.is_none() {
+            self.run()?;
+        }
+        let mut out = self.out.take().unwrap();
+
+        let now = record::now_millis();
+        for &id in segments.iter().filter(|id| **id >= self.started_at.segment) {
+            let mut f = File::open(segment_path(&self.base, id))?;
+            let version = record::read_file_header(&mut f)?;
+            let start = if id == self.started_at.segment {
+                self.started_at.offset.max(version.data_start())
+            } else {
+                version.data_start()
+            };
+
+            let index = &mut self.index;
+            let out_len = &mut self.out_len;
+            let codec = &self.codec;
+            walk_segment(&mut BufReader::new(f), version, start, RecoveryPolicy::Fail, codec, |_, rec, _| {
+                // 过期的也照写：它要盖住文件里同一个 key 更早的值
+                let flags = rec.flags & FLAG_TOMBSTONE;
+                let buf = codec.encode(Version::CURRENT, &rec.kv.key, &rec.kv.value, flags, rec.expires_at)?;
+                out.write_all(&buf)?;
+                if rec.is_tombstone() || rec.is_expired(now) {
+                    index.remove(&rec.kv.key);
+                } else {
+                    index.insert(rec.kv.key, *out_len);
+                }
+                *out_len += buf.len() as u64;
+                Ok(())
+            })?;
+        }
+
+        let f = out.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        self.done = Some(f);
+        Ok(std::mem::take(&mut self.index))
+    }
+
+    /// Moves the new file in as segment `target`, then removes the segments
+    /// it replaces. They go lowest first: if we crash halfway, the ones left
+    /// behind are a suffix of the old log, and replaying them before
+    /// `target` still gives the right answer.
+    pub(crate) fn swap(&self, target: u32, replaced: &[u32]) -> io::Result<()> {
+        let path = compact_path(&self.base);
+        let ours = match (&self.done, fs::metadata(&path)) {
+            (Some(f), Ok(on_disk)) => same_file(&f.metadata()?, &on_disk),
+            (_, Err(err)) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => false,
+        };
+        if !ours {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is gone or was replaced during compaction", path.display()),
+            ));
+        }
+
+        fs::rename(compact_path(&self.base), segment_path(&self.base, target))?;
+        sync_dir(&self.base)?;
+
+        for &id in replaced {
+            fs::remove_file(segment_path(&self.base, id))?;
+        }
+        sync_dir(&self.base)
+    }
+}
+
+/// Removes a `FILE.compact` left behind by a compaction that never
+/// finished, e.g. because the process died.
+pub(crate) fn remove_leftover(base: &Path) -> io::Result<()> {
+    match fs::remove_file(compact_path(base)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Encryption at rest.
+//!
+//! With `Options::encryption_key`, every put and delete is written with
+//! `FLAG_ENCRYPTED`, an empty key field and this as its value:
+//!
+//! nonce     | ciphertext of (key_len u32 | key | value) | tag
+//! [u8; 24]  | [u8; 4 + key_len + value_len]             | [u8; 16]
+//!
+//! The cipher is XChaCha20-Poly1305, with a random nonce per record. The
+//! record's flags and expiry are authenticated along with it, so they can't
+//! be flipped either. Values are compressed before they are encrypted. The
+//! CRC still covers the bytes on disk and catches accidental damage; a
+//! record that was changed and given a matching CRC fails authentication
+//! and comes back as `ActionKvError::Tampered`.
+//!
+//! The hint file holds keys too, so with a key it is sealed the same way as
+//! a whole. Records written without a key stay readable, so an existing log can be
+//! moved over by opening it with a key and compacting it.
+//!
+//! Needs the `encryption` cargo feature.
+
+use std::fmt;
+
+#[cfg(feature = "encryption")]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::error::{ActionKvError, Result};
+use crate::ByteString;
+#[cfg(feature = "encryption")]
+use crate::ByteStr;
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+
+/// The 256-bit key records are encrypted with. Keep it somewhere other than
+/// next to the log.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    #[cfg(feature = "encryption")]
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl EncryptionKey {
+    pub fn new(key: &[u8; 32]) -> Self {
+        EncryptionKey { cipher: XChaCha20Poly1305::new(key.into()) }
+    }
+
+    fn aad(flags: u8, expires_at: Option<u64>) -> [u8; 9] {
+        let mut aad = [0u8; 9];
+        aad[0] = flags;
+        aad[1..].copy_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+        aad
+    }
+
+    /// Encrypts `key` and `value` for a record carrying `flags` (which
+    /// must include `FLAG_ENCRYPTED`) and `expires_at`.
+    pub(crate) fn seal(
+        &self,
+        flags: u8,
+        expires_at: Option<u64>,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> Result<ByteString> {
+        let mut plain = ByteString::with_capacity(4 + key.len() + value.len());
+        plain.write_u32::<LittleEndian>(key.len() as u32)?;
+        plain.extend_from_slice(key);
+        plain.extend_from_slice(value);
+
+        Ok(self.seal_bytes(&plain, &EncryptionKey::aad(flags, expires_at))?)
+    }
+
+    /// `nonce | ciphertext | tag` for `plain`.
+    pub(crate) fn seal_bytes(&self, plain: &[u8], aad: &[u8]) -> std::io::Result<ByteString> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plain, aad })
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+
+        let mut out = ByteString::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend(sealed);
+        Ok(out)
+    }
+
+    /// Undoes `seal_bytes`. `None` if authentication fails.
+    pub(crate) fn open_bytes(&self, sealed: &[u8], aad: &[u8]) -> Option<ByteString> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .ok()
+    }
+}
+
+/// Decrypts the value of the encrypted record at `offset` back into its key
+/// and value.
+pub(crate) fn open(
+    key: Option<&EncryptionKey>,
+    flags: u8,
+    expires_at: Option<u64>,
+    sealed: &[u8],
+    offset: u64,
+) -> Result<(ByteString, ByteString)> {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = key {
+        let aad = EncryptionKey::aad(flags, expires_at);
+        let mut plain = key
+            .open_bytes(sealed, &aad)
+            .ok_or(ActionKvError::Tampered { offset })?;
+
+        // 认证通过了，长度不对只能是写的时候出了 bug
+        let key_len = match (&plain[..]).read_u32::<LittleEndian>() {
+            Ok(len) if 4 + len as usize <= plain.len() => len as usize,
+            _ => return Err(ActionKvError::Tampered { offset }),
+        };
+        let value = plain.split_off(4 + key_len);
+        let key = plain.split_off(4);
+        return Ok((key, value));
+    }
+
+    let _ = (key, flags, expires_at, sealed);
+    Err(ActionKvError::NoEncryptionKey { offset })
+}
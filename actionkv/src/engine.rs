@@ -0,0 +1,127 @@
+//! The operations every storage engine supports, so that code written
+//! against `KvEngine` runs on the bitcask log (`ActionKV`) or entirely in
+//! memory (`MemKV`) without changes.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::error::Result;
+use crate::index::is_empty_range;
+use crate::{ActionKV, ByteStr, ByteString, KeyValuePair, Scan};
+
+/// Pairs coming back from `KvEngine::scan`, in key order.
+pub type EngineScan<'a> = Box<dyn Iterator<Item = Result<KeyValuePair>> + 'a>;
+
+pub trait KvEngine {
+    fn get(&self, key: &ByteStr) -> Result<Option<ByteString>>;
+
+    /// Inserts or replaces `key`.
+    fn put(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()>;
+
+    /// Removes `key`. Deleting a key that isn't there is not an error.
+    fn delete(&mut self, key: &ByteStr) -> Result<()>;
+
+    /// Pairs whose key falls in `range`, in key order. An empty range
+    /// (start after end) gives nothing rather than panicking.
+    fn scan(&self, range: (Bound<&ByteStr>, Bound<&ByteStr>)) -> EngineScan<'_>;
+
+    /// Makes writes so far visible to other readers of the same storage.
+    /// Durability beyond that is up to the engine.
+    fn flush(&mut self) -> Result<()>;
+
+    fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+impl KvEngine for ActionKV {
+    fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        ActionKV::get(self, key)
+    }
+
+    fn put(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.insert(key, value)
+    }
+
+    fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        ActionKV::delete(self, key)
+    }
+
+    fn scan(&self, range: (Bound<&ByteStr>, Bound<&ByteStr>)) -> EngineScan<'_> {
+        Box::new(Scan::new(&self.segments, &self.options.codec, self.index.range(range)))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        ActionKV::flush(self)
+    }
+
+    fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        ActionKV::contains_key(self, key)
+    }
+}
+
+/// A store that only lives in memory: a sorted map, nothing written
+/// anywhere. For tests, and for tools that work on a copy of a store.
+#[derive(Debug, Default, Clone)]
+pub struct MemKV {
+    map: BTreeMap<ByteString, ByteString>,
+}
+
+impl MemKV {
+    pub fn new() -> Self {
+        MemKV::default()
+    }
+
+    /// Copies the live pairs of `engine`, e.g. an `ActionKV` opened
+    /// read-only.
+    pub fn copy_of<E: KvEngine + ?Sized>(engine: &E) -> Result<Self> {
+        let map = engine
+            .scan((Bound::Unbounded, Bound::Unbounded))
+            .map(|kv| kv.map(|kv| (kv.key, kv.value)))
+            .collect::<Result<_>>()?;
+        Ok(MemKV { map })
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvEngine for MemKV {
+    fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.map.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        self.map.remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, range: (Bound<&ByteStr>, Bound<&ByteStr>)) -> EngineScan<'_> {
+        if is_empty_range(&range) {
+            return Box::new(std::iter::empty());
+        }
+        let pairs = self
+            .map
+            .range::<ByteStr, _>(range)
+            .map(|(key, value)| Ok(KeyValuePair { key: key.clone(), value: value.clone() }));
+        Box::new(pairs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        Ok(self.map.contains_key(key))
+    }
+}
@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::typed::Format;
+
+#[derive(Debug)]
+pub enum ActionKvError {
+    Io(io::Error),
+    /// A record's checksum did not match its contents.
+    Corruption { offset: u64, expected: u32, actual: u32 },
+    /// Keys and values are stored with a u32 length.
+    KeyTooLarge { len: usize },
+    ValueTooLarge { len: usize },
+    UnsupportedVersion(u32),
+    /// The record's value was compressed with a codec this build leaves
+    /// out. See the `compression` feature.
+    UnsupportedCodec { offset: u64, flags: u8 },
+    /// An encrypted record failed authentication: it was changed after it
+    /// was written, or the store was opened with the wrong key.
+    Tampered { offset: u64 },
+    /// The record is encrypted and the store was opened without a key.
+    NoEncryptionKey { offset: u64 },
+    /// The active segment ends in `len` bytes of a record (or file header)
+    /// that was never finished, starting at `offset`. Opening for writing
+    /// refuses to append after it unless `Options::repair_torn_tail` is set.
+    TornTail { offset: u64, len: u64 },
+    /// Another process, or another handle in this one, has the store open
+    /// for writing. See `Options::read_only`.
+    StoreLocked { path: PathBuf },
+    /// A typed key or value could not be encoded, or stored bytes could not
+    /// be decoded as the type asked for. See `TypedStore`.
+    Serialization { format: Format, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, ActionKvError>;
+
+/// Exit codes shared by the command-line tools, so that shell scripts can
+/// branch on the result. See `ActionKvError::exit_code`.
+pub mod exit {
+    pub const NOT_FOUND: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const IO: i32 = 3;
+    pub const CORRUPT: i32 = 4;
+    pub const LOCKED: i32 = 5;
+}
+
+impl fmt::Display for ActionKvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionKvError::Io(err) => write!(f, "i/o error: {}", err),
+            ActionKvError::Corruption { offset, expected, actual } => write!(
+                f,
+                "data corruption encountered at offset {} ({:08x} != {:08x})",
+                offset, actual, expected
+            ),
+            ActionKvError::KeyTooLarge { len } => write!(f, "key of {} bytes is too large", len),
+            ActionKvError::ValueTooLarge { len } => write!(f, "value of {} bytes is too large", len),
+            ActionKvError::UnsupportedVersion(v) => write!(f, "unsupported log version {}", v),
+            ActionKvError::UnsupportedCodec { offset, flags } => write!(
+                f,
+                "record at offset {} uses a codec this build does not support (flags {:02x})",
+                offset, flags
+            ),
+            ActionKvError::Tampered { offset } => write!(
+                f,
+                "record at offset {} failed authentication (tampered with, or wrong key)",
+                offset
+            ),
+            ActionKvError::NoEncryptionKey { offset } => {
+                write!(f, "record at offset {} is encrypted and no key was given", offset)
+            }
+            ActionKvError::TornTail { offset, len } => write!(
+                f,
+                "log ends in {} bytes of an unfinished record at offset {} (see Options::repair_torn_tail)",
+                len, offset
+            ),
+            ActionKvError::StoreLocked { path } => {
+                write!(f, "{} is already open for writing elsewhere", path.display())
+            }
+            ActionKvError::Serialization { format, message } => {
+                write!(f, "{:?} serialization failed: {}", format, message)
+            }
+        }
+    }
+}
+
+impl Error for ActionKvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActionKvError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ActionKvError {
+    fn from(err: io::Error) -> Self {
+        ActionKvError::Io(err)
+    }
+}
+
+impl ActionKvError {
+    /// What a command-line tool exits with when it fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ActionKvError::Io(_) => exit::IO,
+            ActionKvError::Corruption { .. }
+            | ActionKvError::UnsupportedVersion(_)
+            | ActionKvError::UnsupportedCodec { .. }
+            | ActionKvError::Tampered { .. }
+            | ActionKvError::NoEncryptionKey { .. }
+            | ActionKvError::TornTail { .. }
+            | ActionKvError::Serialization { .. } => exit::CORRUPT,
+            ActionKvError::KeyTooLarge { .. } | ActionKvError::ValueTooLarge { .. } => exit::USAGE,
+            ActionKvError::StoreLocked { .. } => exit::LOCKED,
+        }
+    }
+
+    /// The same error again, for one more caller: every writer in a group
+    /// commit that failed gets one. An I/O error keeps its kind and message
+    /// but not its source.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            ActionKvError::Io(err) => ActionKvError::Io(io::Error::new(err.kind(), err.to_string())),
+            ActionKvError::Corruption { offset, expected, actual } => {
+                ActionKvError::Corruption { offset: *offset, expected: *expected, actual: *actual }
+            }
+            ActionKvError::KeyTooLarge { len } => ActionKvError::KeyTooLarge { len: *len },
+            ActionKvError::ValueTooLarge { len } => ActionKvError::ValueTooLarge { len: *len },
+            ActionKvError::UnsupportedVersion(v) => ActionKvError::UnsupportedVersion(*v),
+            ActionKvError::UnsupportedCodec { offset, flags } => {
+                ActionKvError::UnsupportedCodec { offset: *offset, flags: *flags }
+            }
+            ActionKvError::Tampered { offset } => ActionKvError::Tampered { offset: *offset },
+            ActionKvError::NoEncryptionKey { offset } => ActionKvError::NoEncryptionKey { offset: *offset },
+            ActionKvError::TornTail { offset, len } => ActionKvError::TornTail { offset: *offset, len: *len },
+            ActionKvError::StoreLocked { path } => ActionKvError::StoreLocked { path: path.clone() },
+            ActionKvError::Serialization { format, message } => {
+                ActionKvError::Serialization { format: *format, message: message.clone() }
+            }
+        }
+    }
+
+
+    /// A clean end of file or a record cut short by it.
+    pub(crate) fn is_eof(&self) -> bool {
+        matches!(self, ActionKvError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
@@ -0,0 +1,113 @@
+//! Group commit: many writers, one write and one fsync.
+//!
+//! Writers hand their puts and deletes to a single writer thread over a
+//! channel and wait. The writer thread takes whatever has queued up (up to
+//! `MAX_GROUP` writes), appends it all with `ActionKV::append_group`, syncs
+//! once, and then tells each writer where its record landed. Under load the
+//! cost of a sync is shared by the whole group, and a writer is only told
+//! its write went through once it is on disk, whatever the store's
+//! `SyncPolicy`.
+
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::error::Result;
+use crate::record;
+use crate::{ActionKvError, ByteStr, ByteString, Position, SharedKV};
+
+/// The most writes appended together. Bounds how long the first writer in
+/// a group waits on the ones behind it.
+const MAX_GROUP: usize = 1024;
+
+struct Request {
+    key: ByteString,
+    /// `None` for a delete.
+    value: Option<ByteString>,
+    reply: SyncSender<Result<Position>>,
+}
+
+/// A handle for writing to a store through its group-commit thread. Clone
+/// it for every thread that writes. Dropping the last handle waits for the
+/// writer thread to finish, so that the store is closed (and its lock let
+/// go, if nothing else holds it) by the time the drop returns.
+///
+/// Reads, and writes that don't go through a `GroupCommit`, still work on
+/// the `SharedKV` as before.
+#[derive(Debug, Clone)]
+pub struct GroupCommit {
+    // 顺序要紧：先丢掉 Sender，writer 线程才会退出，然后 Writer 再等它
+    requests: Sender<Request>,
+    _writer: Arc<Writer>,
+}
+
+#[derive(Debug)]
+struct Writer(Option<JoinHandle<()>>);
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.0.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl GroupCommit {
+    /// Starts the writer thread for `store`.
+    pub fn new(store: SharedKV) -> Self {
+        let (requests, incoming) = mpsc::channel();
+        let thread = thread::spawn(move || commit_groups(store, incoming));
+        GroupCommit { requests, _writer: Arc::new(Writer(Some(thread))) }
+    }
+
+    /// Inserts `key`, returning once the record is synced to disk.
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<Position> {
+        self.submit(key, Some(value))
+    }
+
+    /// Deletes `key`, returning once the tombstone is synced to disk.
+    pub fn delete(&self, key: &ByteStr) -> Result<Position> {
+        self.submit(key, None)
+    }
+
+    fn submit(&self, key: &ByteStr, value: Option<&ByteStr>) -> Result<Position> {
+        // 在这里先查长度，免得一条写不了的记录连累同组的其它写
+        record::check_lengths(key, value.unwrap_or_default())?;
+
+        let (reply, response) = mpsc::sync_channel(1);
+        let request = Request { key: key.to_vec(), value: value.map(<[u8]>::to_vec), reply };
+        if self.requests.send(request).is_err() {
+            return Err(writer_gone());
+        }
+        response.recv().unwrap_or_else(|_| Err(writer_gone()))
+    }
+}
+
+fn writer_gone() -> ActionKvError {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the group commit thread has stopped").into()
+}
+
+fn commit_groups(store: SharedKV, incoming: Receiver<Request>) {
+    while let Ok(first) = incoming.recv() {
+        let mut group = vec![first];
+        group.extend(incoming.try_iter().take(MAX_GROUP - 1));
+
+        let writes: Vec<(&ByteStr, Option<&ByteStr>)> =
+            group.iter().map(|r| (r.key.as_slice(), r.value.as_deref())).collect();
+        let result = store.write().append_group(&writes);
+
+        // 写者可能已经不等了，发不出去就算了
+        match result {
+            Ok(positions) => {
+                for (request, position) in group.iter().zip(positions) {
+                    let _ = request.reply.send(Ok(position));
+                }
+            }
+            Err(err) => {
+                for request in &group {
+                    let _ = request.reply.send(Err(err.duplicate()));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Hint file: a persisted copy of the in-memory index.
+//!
+//! hint format:
+//! checksum | magic   | end_segment | end_offset | tail | count | entries...
+//! u32      | [u8; 8] | u32         | u64        | u32  | u64   |
+//!
+//! entry:
+//! key_len | key           | segment | offset
+//! u32     | [u8; key_len] | u32     | u64
+//!
+//! `end_*` is where the log ended when the hint was taken, and `tail` the
+//! CRC of the bytes just before it (see `tail_checksum`). A log that was
+//! cut short and then grew back past `end_*` fails that check. The checksum
+//! covers everything after itself, just like a record's checksum covers its
+//! key and value. Hints with another magic, from before segments or tail
+//! checksums existed, are ignored.
+//!
+//! A store with an encryption key writes `ENCRYPTED_MAGIC` followed by the
+//! hint above (from the magic on) sealed with that key, and checksums that
+//! instead. See `crypto`.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::crypto::EncryptionKey;
+use crate::index::Index;
+use crate::record::Version;
+use crate::segment::{Position, ReadAt};
+use crate::ByteString;
+
+const MAGIC: &[u8; 8] = b"AKVHINT3";
+/// How many bytes before the end of the log `tail_checksum` covers.
+const TAIL_LEN: u64 = 64;
+#[cfg(feature = "encryption")]
+const ENCRYPTED_MAGIC: &[u8; 8] = b"AKVHINTE";
+
+/// The hint lives next to the data file: `FILE.hint`.
+pub(crate) fn hint_path(path: &Path) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(".hint");
+    PathBuf::from(name)
+}
+
+/// A hint as read back by `read`.
+pub(crate) struct Hint {
+    /// Where the log ended when the hint was taken.
+    pub end: Position,
+    /// `tail_checksum` of the log at `end` back then.
+    pub tail: u32,
+    pub index: HashMap<ByteString, Position>,
+}
+
+/// CRC of the last `TAIL_LEN` bytes (fewer if the segment has fewer
+/// records) before `end` in `f`.
+pub(crate) fn tail_checksum(f: &File, version: Version, end: u64) -> io::Result<u32> {
+    let start = end.saturating_sub(TAIL_LEN).max(version.data_start());
+    let mut bytes = Vec::with_capacity(TAIL_LEN as usize);
+    ReadAt::new(f, start).take(end - start).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < end - start {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(crc32::checksum_ieee(&bytes))
+}
+
+pub(crate) fn write(
+    path: &Path,
+    end: Position,
+    tail: u32,
+    index: &Index,
+    key: Option<&EncryptionKey>,
+) -> io::Result<()> {
+    let mut body = ByteString::new();
+    body.write_all(MAGIC)?;
+    body.write_u32::<LittleEndian>(end.segment)?;
+    body.write_u64::<LittleEndian>(end.offset)?;
+    body.write_u32::<LittleEndian>(tail)?;
+    body.write_u64::<LittleEndian>(index.len() as u64)?;
+    for (key, position) in index.iter() {
+        body.write_u32::<LittleEndian>(key.len() as u32)?;
+        body.write_all(key)?;
+        body.write_u32::<LittleEndian>(position.segment)?;
+        body.write_u64::<LittleEndian>(position.offset)?;
+    }
+
+    #[cfg(feature = "encryption")]
+    if let Some(key) = key {
+        let sealed = key.seal_bytes(&body, ENCRYPTED_MAGIC)?;
+        body = ENCRYPTED_MAGIC.to_vec();
+        body.extend(sealed);
+    }
+    let _ = key;
+
+    let checksum = crc32::checksum_ieee(&body);
+
+    // 先写临时文件再 rename，崩溃时不会留下写了一半的 hint
+    let target = hint_path(path);
+    let mut tmp_name = target.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    {
+        let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+        f.write_u32::<LittleEndian>(checksum)?;
+        f.write_all(&body)?;
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    fs::rename(&tmp, &target)
+}
+
+/// Drops the hint, e.g. when the log it describes is replaced.
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(hint_path(path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// A missing, truncated or corrupt hint is reported as `None`: the caller
+/// can always rebuild the index from the log, so it is never fatal.
+pub(crate) fn read(
+    path: &Path,
+    key: Option<&EncryptionKey>,
+) -> io::Result<Option<Hint>> {
+    let bytes = match fs::read(hint_path(path)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    Ok(decode(&bytes, key).ok())
+}
+
+fn decode(bytes: &[u8], key: Option<&EncryptionKey>) -> io::Result<Hint> {
+    let mut f = bytes;
+    let saved_checksum = f.read_u32::<LittleEndian>()?;
+    if crc32::checksum_ieee(f) != saved_checksum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hint checksum mismatch"));
+    }
+
+    #[cfg(feature = "encryption")]
+    let opened;
+    #[cfg(feature = "encryption")]
+    if let (Some(key), Some(sealed)) = (key, f.strip_prefix(ENCRYPTED_MAGIC)) {
+        opened = key
+            .open_bytes(sealed, ENCRYPTED_MAGIC)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hint failed authentication"))?;
+        f = &opened;
+    }
+    let _ = key;
+
+    if f.len() < MAGIC.len() || &f[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a hint file"));
+    }
+    f = &f[MAGIC.len()..];
+
+    let end = Position::new(f.read_u32::<LittleEndian>()?, f.read_u64::<LittleEndian>()?);
+    let tail = f.read_u32::<LittleEndian>()?;
+    let count = f.read_u64::<LittleEndian>()?;
+    // count 不可信，按剩下的字节最多能装几条来预留：每条至少有 key 长度和位置共 16 字节
+    let mut index = HashMap::with_capacity(count.min(f.len() as u64 / 16) as usize);
+    for _ in 0..count {
+        let key_len = f.read_u32::<LittleEndian>()? as usize;
+        if f.len() < key_len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "hint truncated"));
+        }
+        let (key, rest) = f.split_at(key_len);
+        f = rest;
+        let position = Position::new(f.read_u32::<LittleEndian>()?, f.read_u64::<LittleEndian>()?);
+        index.insert(key.to_vec(), position);
+    }
+
+    Ok(Hint { end, tail, index })
+}
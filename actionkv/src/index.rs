@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+use crate::segment::Position;
+use crate::{ByteStr, ByteString};
+
+type Entry<'a> = (&'a ByteString, &'a Position);
+
+/// Where each live key's latest record is. A `HashMap` by default; an
+/// ordered `BTreeMap` when the store is opened with
+/// `Options::ordered_index`, which makes range and prefix scans cheap.
+#[derive(Debug, Clone)]
+pub enum Index {
+    Hash(HashMap<ByteString, Position>),
+    Ordered(BTreeMap<ByteString, Position>),
+}
+
+impl Index {
+    pub fn new(ordered: bool) -> Self {
+        if ordered {
+            Index::Ordered(BTreeMap::new())
+        } else {
+            Index::Hash(HashMap::new())
+        }
+    }
+
+    pub(crate) fn from_map(map: HashMap<ByteString, Position>, ordered: bool) -> Self {
+        if ordered {
+            Index::Ordered(map.into_iter().collect())
+        } else {
+            Index::Hash(map)
+        }
+    }
+
+    pub fn is_ordered(&self) -> bool {
+        matches!(self, Index::Ordered(_))
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Option<&Position> {
+        match self {
+            Index::Hash(map) => map.get(key),
+            Index::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: ByteString, position: Position) -> Option<Position> {
+        match self {
+            Index::Hash(map) => map.insert(key, position),
+            Index::Ordered(map) => map.insert(key, position),
+        }
+    }
+
+    pub fn remove(&mut self, key: &ByteStr) -> Option<Position> {
+        match self {
+            Index::Hash(map) => map.remove(key),
+            Index::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Hash(map) => map.len(),
+            Index::Ordered(map) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every entry, in no particular order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_>> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter()),
+            Index::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    /// Entries whose key falls in `range`, in key order. A hash index has
+    /// to collect and sort the matches first.
+    pub fn range<'a>(
+        &'a self,
+        range: (Bound<&ByteStr>, Bound<&ByteStr>),
+    ) -> Box<dyn Iterator<Item = Entry<'a>> + 'a> {
+        if is_empty_range(&range) {
+            return Box::new(std::iter::empty());
+        }
+
+        match self {
+            Index::Ordered(map) => Box::new(map.range::<ByteStr, _>(range)),
+            Index::Hash(map) => {
+                let mut entries: Vec<Entry<'a>> = map
+                    .iter()
+                    .filter(|(key, _)| RangeBounds::<ByteStr>::contains(&range, key.as_slice()))
+                    .collect();
+                entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                Box::new(entries.into_iter())
+            }
+        }
+    }
+
+    /// Entries whose key starts with `prefix`, in key order.
+    pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Box<dyn Iterator<Item = Entry<'a>> + 'a> {
+        let entries = self
+            .range((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix));
+        Box::new(entries)
+    }
+}
+
+/// `BTreeMap::range` panics on these instead of returning nothing.
+pub(crate) fn is_empty_range(range: &(Bound<&ByteStr>, Bound<&ByteStr>)) -> bool {
+    match *range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
@@ -1,22 +1,91 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::fs::{ File, OpenOptions };
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
+use std::io::{BufReader, SeekFrom};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+mod batch;
+mod check;
+mod client;
+mod codec;
+mod compact;
+mod crypto;
+mod engine;
+mod error;
+mod group;
+mod hint;
+mod index;
+mod lock;
+mod mmap;
+mod options;
+mod record;
+mod replication;
+mod resp;
+mod secondary;
+mod segment;
+mod server;
+mod shared;
+mod snapshot;
+mod subscribe;
+mod typed;
+mod walk;
 
-type ByteString = Vec<u8>;
-type ByteStr = [u8];
+use codec::Codec;
+use record::{Record, FLAG_TOMBSTONE};
+use secondary::SecondaryIndexes;
+pub use batch::WriteBatch;
+pub use check::{LogCheck, RecordCheck, RecordStatus};
+pub use client::Client;
+pub use codec::Compression;
+use segment::Segment;
+pub use compact::Compaction;
+#[cfg(feature = "encryption")]
+pub use crypto::EncryptionKey;
+pub use engine::{EngineScan, KvEngine, MemKV};
+pub use error::{exit, ActionKvError, Result};
+pub use group::GroupCommit;
+pub use index::Index;
+pub use mmap::ValueRef;
+pub use options::{Options, RecoveryPolicy, SyncPolicy};
+pub use record::Version;
+pub use replication::{Follower, Leader};
+pub use segment::Position;
+pub use server::Server;
+pub use shared::SharedKV;
+pub use snapshot::Snapshot;
+pub use subscribe::{Change, ChangeKind, Subscription};
+pub use typed::{Format, TypedIter, TypedStore};
 
-/// 维护一个文件，以及key在文件中的位置
+pub(crate) type ByteString = Vec<u8>;
+pub(crate) type ByteStr = [u8];
+
+/// 维护一组段文件，以及key在文件中的位置
 #[derive(Debug)] // #[derive(Debug)]
 pub struct ActionKV {
+    /// The active (last) segment, opened for appending.
     f: File,
-    pub index: HashMap<ByteString, u64>,
+    path: PathBuf,
+    /// Format of the active segment.
+    version: Version,
+    /// Every segment, lowest id first. The last one is the active one.
+    segments: Vec<Arc<Segment>>,
+    options: Options,
+    torn_bytes: u64,
+    /// Writes to the active segment since it was last synced.
+    unsynced: u32,
+    last_sync: Instant,
+    pub index: Index,
+    /// Indexes added with `add_index`.
+    secondary: SecondaryIndexes,
+    /// The writer lock, held until the store is dropped. `None` when it was
+    /// opened read-only.
+    _lock: Option<File>,
+    /// Whether a `Compaction` of this store is in progress.
+    compacting: compact::Running,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,190 +95,2614 @@ pub struct KeyValuePair {
 }
 
 
+/// Writes the sync policy hasn't synced yet are synced when the store is
+/// dropped, unless the policy is `SyncPolicy::Never`. Errors are lost; call
+/// `sync` first to see them.
+impl Drop for ActionKV {
+    fn drop(&mut self) {
+        if self.unsynced > 0 && self.options.sync != SyncPolicy::Never {
+            let _ = self.sync();
+        }
+    }
+}
+
 impl ActionKV {
-    pub fn open(path: &Path) -> io::Result<Self> {
-        let f = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(path)?;
-        let index = HashMap::new();
+    /// Opens (or creates) a store. New segments are written in the current
+    /// format; logs from before the file header existed keep their format.
+    pub fn open(path: &Path) -> Result<Self> {
+        ActionKV::open_with(path, Options::default())
+    }
+
+    pub(crate) fn open_with(path: &Path, options: Options) -> Result<Self> {
+        // 先拿到锁，再碰任何文件
+        let lock = if options.read_only { None } else { Some(lock::acquire(path)?) };
+
+        let mut ids = segment::list_segments(path)?;
+        if ids.is_empty() {
+            if options.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no store at {}", path.display()),
+                ).into());
+            }
+            ids.push(0);
+        }
+        let segments: Vec<Arc<Segment>> = ids.iter().map(|id| Arc::new(Segment::new(path, *id))).collect();
+        let active = segments.last().unwrap();
+
+        let mut f = if options.read_only {
+            File::open(&active.path)?
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .create(true)
+                .append(true)
+                .open(&active.path)?
+        };
+
+        let mut torn_bytes = 0;
+        let mut len = f.metadata()?.len();
+        if len < record::FILE_HEADER_LEN {
+            let mut head = Vec::new();
+            f.seek(SeekFrom::Start(0))?;
+            f.read_to_end(&mut head)?;
+            if record::is_torn_file_header(&head) {
+                // 只读的话修不了，也不能当成 v1 日志去读
+                if !options.repair_torn_tail || options.read_only {
+                    return Err(ActionKvError::TornTail { offset: 0, len });
+                }
+                f.set_len(0)?;
+                torn_bytes = len;
+                len = 0;
+            }
+        }
+
+        let version = if len == 0 {
+            // 只读的话，写者马上就会写文件头
+            if !options.read_only {
+                record::write_file_header(&mut f, Version::CURRENT)?;
+            }
+            Version::CURRENT
+        } else {
+            f.seek(SeekFrom::Start(0))?;
+            record::read_file_header(&mut f)?
+        };
+
+        let index = Index::new(options.ordered_index);
+
+        let mut store = ActionKV {
+            f,
+            path: path.to_path_buf(),
+            version,
+            segments,
+            options,
+            torn_bytes,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            index,
+            secondary: SecondaryIndexes::default(),
+            _lock: lock,
+            compacting: compact::Running::default(),
+        };
+        // 修不修都要先看尾巴：接在撕裂的记录后面写，下次 load 就读不到了
+        if !store.options.read_only {
+            store.torn_bytes += store.repair_torn_tail()?;
+        }
+
+        Ok(store)
+    }
+
+    /// Whether the store was opened with `Options::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} was opened read-only", self.path.display()),
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// How many bytes `Options::repair_torn_tail` cut off when opening.
+    pub fn torn_bytes_dropped(&self) -> u64 {
+        self.torn_bytes
+    }
+
+    /// Finds where the last complete record in the active segment ends and
+    /// truncates anything after it that cannot be read. Returns how many
+    /// bytes were dropped. Without `Options::repair_torn_tail`, a torn tail
+    /// is `ActionKvError::TornTail` instead.
+    ///
+    /// A bad record in the middle of the log is left alone for `load` and the
+    /// recovery policy to deal with; only a tail that runs into the end of
+    /// the file counts as torn. So does a batch that never committed.
+    fn repair_torn_tail(&mut self) -> Result<u64> {
+        let end = self.end()?;
+        let start = match self.valid_hint(end)? {
+            Some(hint) if hint.end.segment == end.segment => hint.end.offset,
+            _ => self.version.data_start(),
+        };
+
+        // 解不开的完整记录（没有 key、认证失败）不算撕裂，留给 load
+        let walked = walk::walk_past_damage(
+            &self.f,
+            self.version,
+            start,
+            &self.options.codec,
+            |_, _, _| Ok(()),
+            |_| {},
+        )?;
+        let good_end = walked.good_end;
+
+        if good_end < end.offset {
+            if !self.options.repair_torn_tail {
+                return Err(ActionKvError::TornTail { offset: good_end, len: end.offset - good_end });
+            }
+            self.f.set_len(good_end)?;
+            self.f.sync_all()?;
+        }
+
+        Ok(end.offset - good_end)
+    }
+
+    /// Format of the active segment.
+    pub fn version(&self) -> Version {
+        self.version
+    }
 
-        Ok(ActionKV { f, index})
+    /// Ids of the segments that make up the store, oldest first.
+    pub fn segment_ids(&self) -> Vec<u32> {
+        self.segments.iter().map(|s| s.id).collect()
     }
 
+    /// Offset of the end of the active segment.
     pub fn seek_to_end(&mut self) -> io::Result<u64> {
         self.f.seek(SeekFrom::End(0))
     }
 
+    /// Where the next record will be written, unless the active segment
+    /// rolls over first.
+    pub fn end(&mut self) -> io::Result<Position> {
+        let offset = self.seek_to_end()?;
+        Ok(Position::new(self.active_id(), offset))
+    }
+
+    fn active_id(&self) -> u32 {
+        self.segments.last().unwrap().id
+    }
+
+    fn segment(&self, id: u32) -> Result<&Segment> {
+        segment::find(&self.segments, id)
+    }
+
     pub fn get_at(
-        &mut self,
-        position: u64
-    ) -> io::Result<KeyValuePair> {
-        let mut f = BufReader::new(&mut self.f);
-        f.seek(SeekFrom::Start(position))?;
-        let kv = ActionKV::process_record(&mut f)?;
+        &self,
+        position: Position
+    ) -> Result<KeyValuePair> {
+        Ok(self.record_at(position)?.kv)
+    }
 
-        Ok(kv)
+    fn record_at(&self, position: Position) -> Result<Record> {
+        #[cfg(feature = "mmap")]
+        if self.options.mmap {
+            return mmap::read_record(&self.segments, position, &self.options.codec);
+        }
+        segment::read_record(&self.segments, position, &self.options.codec)
     }
 
     pub fn get(
-        &mut self,
+        &self,
         key: &ByteStr
-    ) -> io::Result<Option<ByteString>> {
+    ) -> Result<Option<ByteString>> {
+        Ok(self.get_ref(key)?.map(ValueRef::into_vec))
+    }
+
+    /// `get`, without copying the value where that can be avoided: with
+    /// `Options::mmap`, a value stored as is in a sealed segment comes back
+    /// as a slice of the mapped segment. Otherwise it is read the way `get`
+    /// reads it.
+    pub fn get_ref(&self, key: &ByteStr) -> Result<Option<ValueRef>> {
         let position = match self.index.get(key) {
             None => return Ok(None),
             Some(position) => *position,
         };
 
-        let kv = self.get_at(position)?;
+        #[cfg(feature = "mmap")]
+        if self.options.mmap {
+            let (expires_at, value) = mmap::read_value(&self.segments, position, key, &self.options.codec)?;
+            return Ok(Some(value).filter(|_| !record::is_expired(expires_at, record::now_millis())));
+        }
+
+        let record = self.record_at(position)?;
+        segment::expect_key(position, key, &record.kv.key)?;
+        if record.is_expired(record::now_millis()) {
+            return Ok(None);
+        }
 
-        Ok(Some(kv.value))
+        Ok(Some(ValueRef::owned(record.kv.value)))
     }
 
-    pub fn load(&mut self) -> io::Result<()> {
-        let mut f = BufReader::new(&mut self.f);
+    /// Whether `key` has a value, i.e. is in the index and hasn't expired.
+    /// Reads the record to check its expiry, unlike `index.contains_key`.
+    pub fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        Ok(self.get_ref(key)?.is_some())
+    }
 
-        loop {
-            let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = ActionKV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv, 
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        },
-                        _ => return Err(err),
-                    }
-                }
-            };
+    /// Rebuilds the index. A hint file written by `write_hint` is used when
+    /// it is still valid; records appended after it was taken are scanned
+    /// on top. A stale (log got shorter or was rewritten) or corrupt hint
+    /// means a full scan.
+    /// Indexes added with `add_index` are rebuilt after that, which reads
+    /// every live value.
+    pub fn load(&mut self) -> Result<()> {
+        let end = self.end()?;
 
-            self.index.insert(kv.key, position);
-        }
+        let start = match self.valid_hint(end)? {
+            Some(hint) => {
+                self.index = Index::from_map(hint.index, self.options.ordered_index);
+                hint.end
+            }
+            _ => {
+                self.index = Index::new(self.options.ordered_index);
+                Position::new(self.segments[0].id, 0)
+            }
+        };
+
+        self.scan_from(start)?;
+        self.secondary.clear();
+        self.index_values(None)
+    }
+
+    fn scan_from(&mut self, start: Position) -> Result<()> {
+        let mut index = std::mem::replace(&mut self.index, Index::new(false));
+        let now = record::now_millis();
+        let result = self.walk_log(start, |position, record| {
+            if record.is_tombstone() || record.is_expired(now) {
+                index.remove(&record.kv.key);
+            } else {
+                index.insert(record.kv.key, position);
+            }
+        });
+        self.index = index;
+        result.map(|_| ())
+    }
 
+    /// Persists the index next to the data file so the next `load` can skip
+    /// scanning the log. Syncs the log first: a hint must never describe
+    /// records that a power failure can still take away.
+    pub fn write_hint(&mut self) -> Result<()> {
+        self.writable()?;
+        self.sync()?;
+        let end = self.end()?;
+        let tail = hint::tail_checksum(&self.f, self.version, end.offset)?;
+        hint::write(&self.path, end, tail, &self.index, self.options.codec.key.as_ref())?;
         Ok(())
     }
 
+    /// The hint, if it still describes the start of the log that ends at
+    /// `end`. A hint that doesn't is removed (unless the store is
+    /// read-only), so that a log growing back past where it ended can't
+    /// make it look valid again.
+    fn valid_hint(&self, end: Position) -> Result<Option<hint::Hint>> {
+        let hint = match hint::read(&self.path, self.options.codec.key.as_ref())? {
+            Some(hint) => hint,
+            None => return Ok(None),
+        };
+
+        let valid = hint.end <= end
+            && match self.segment(hint.end.segment) {
+                Ok(segment) => {
+                    let (f, version) = segment.reader()?;
+                    hint::tail_checksum(f, version, hint.end.offset)? == hint.tail
+                }
+                Err(_) => false,
+            };
+        if valid {
+            return Ok(Some(hint));
+        }
+        if !self.options.read_only {
+            hint::remove(&self.path)?;
+        }
+        Ok(None)
+    }
+
     pub fn insert(
         &mut self,
         key: &ByteStr,
         value: &ByteStr,
-    ) -> io::Result<()> {
-        let position = self.insert_but_ignore_index(key, value)?;
+    ) -> Result<()> {
+        self.insert_expiring(key, value, None)
+    }
+
+    pub fn insert_but_ignore_index (
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> Result<Position> {
+        self.append(key, value, 0, None)
+    }
+
+    /// Inserts `key` so that it reads as missing once `ttl` has passed:
+    /// `get` and `contains_key` check the expiry. It stays in `index` until
+    /// a `load` that scans its record (not one that takes the index from a
+    /// hint) or a compaction drops it.
+    pub fn insert_with_ttl(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        ttl: Duration,
+    ) -> Result<()> {
+        let ttl = ttl.as_millis().min(u64::MAX as u128) as u64;
+        let expires_at = record::now_millis().saturating_add(ttl).max(1);
+        self.insert_expiring(key, value, Some(expires_at))
+    }
+
+    /// `insert`, with the expiry as a time rather than a TTL.
+    pub(crate) fn insert_expiring(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let position = self.append(key, value, 0, expires_at)?;
 
         self.index.insert(key.to_vec(), position);
+        self.secondary.insert(key, value);
         Ok( () )
     }
 
-    pub fn insert_but_ignore_index (
+    fn append(
         &mut self,
         key: &ByteStr,
         value: &ByteStr,
-    ) -> io::Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
+        flags: u8,
+        expires_at: Option<u64>,
+    ) -> Result<Position> {
+        self.writable()?;
+        // 只有 v3 段能存过期时间，加密至少要 v2 的 flags；
+        // v1 里空值就是墓碑，所以空值也要 v2
+        let empty_value = value.is_empty() && flags & FLAG_TOMBSTONE == 0;
+        let needs = if expires_at.is_some() {
+            Version::V3
+        } else if self.options.codec.needs_flags() || empty_value {
+            Version::V2
+        } else {
+            Version::V1
+        };
+        if self.version < needs {
+            self.roll_over()?;
+        }
+        let version = self.version;
+        let mut buf = self.encode(version, key, value, flags, expires_at)?;
+
+        let current_position = self.make_room(buf.len() as u64)?;
+        if self.version != version {
+            buf = self.encode(self.version, key, value, flags, expires_at)?;
+        }
+
+        self.f.write_all(&buf)?;
+        self.wrote()?;
+
+        Ok(current_position)
+    }
+
+    fn encode(
+        &self,
+        version: Version,
+        key: &ByteStr,
+        value: &ByteStr,
+        flags: u8,
+        expires_at: Option<u64>,
+    ) -> Result<ByteString> {
+        self.options.codec.encode(version, key, value, flags, expires_at)
+    }
 
-        let key_len = key.len();
-        let val_len = value.len();
-        let mut tmp = ByteString::with_capacity(key_len + val_len);
+    /// Rolls the active segment over if `len` more bytes would take it past
+    /// the segment size. Returns where those bytes will go.
+    fn make_room(&mut self, len: u64) -> Result<Position> {
+        let current_position = self.end()?;
+        if let Some(max) = self.options.segment_size {
+            let has_records = current_position.offset > self.version.data_start();
+            if has_records && current_position.offset + len > max {
+                self.roll_over()?;
+                return Ok(self.end()?);
+            }
+        }
+        Ok(current_position)
+    }
 
-        for byte in key {
-            tmp.push(*byte);
+    /// Appends every op in `batch` with a single write. After a crash either
+    /// all of them are in the log or none are.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        self.writable()?;
+        if batch.is_empty() {
+            return Ok(());
         }
+        // v1 段没有 flags，写不了 batch 标记
+        if self.version < Version::V2 {
+            self.roll_over()?;
+        }
+
+        let version = self.version;
+        let codec = &self.options.codec;
+        let (mut buf, mut ops) = batch::encode(version, codec, batch)?;
+        let start = self.make_room(buf.len() as u64)?;
+        if self.version != version {
+            (buf, ops) = batch::encode(self.version, &self.options.codec, batch)?;
+        }
+        self.f.write_all(&buf)?;
+        self.wrote()?;
 
-        for byte in value {
-            tmp.push(*byte);
+        for op in ops {
+            if op.tombstone {
+                self.index.remove(&op.key);
+            } else {
+                self.index.insert(op.key, Position::new(start.segment, start.offset + op.offset));
+            }
+        }
+        for (key, value) in batch.ops() {
+            match value {
+                Some(value) => self.secondary.insert(key, value),
+                None => self.secondary.remove(key),
+            }
         }
 
-        let checksum = crc32::checksum_ieee(&tmp);
-        
-        let next_byte = SeekFrom::End(0);
-        let current_position = f.seek(SeekFrom::Current(0))?;
-        f.seek(next_byte)?;
+        Ok(())
+    }
 
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(val_len as u32)?;
-        f.write_all(&mut tmp)?;
+    /// Appends `writes`, each a put or (with no value) a delete, with a
+    /// single write and a single sync, and returns where each one landed.
+    /// Unlike `write_batch` the writes stand alone: a crash partway through
+    /// keeps the ones before the tear. See `GroupCommit`.
+    pub(crate) fn append_group(&mut self, writes: &[(&ByteStr, Option<&ByteStr>)]) -> Result<Vec<Position>> {
+        self.writable()?;
+        if writes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let empty_value = writes.iter().any(|(_, value)| value.is_some_and(<[u8]>::is_empty));
+        if (self.options.codec.needs_flags() || empty_value) && self.version < Version::V2 {
+            self.roll_over()?;
+        }
 
-        Ok(current_position)
+        let encode_all = |store: &Self, version| -> Result<(ByteString, Vec<u64>)> {
+            let mut buf = ByteString::new();
+            let mut offsets = Vec::with_capacity(writes.len());
+            for (key, value) in writes {
+                let flags = if value.is_none() { FLAG_TOMBSTONE } else { 0 };
+                offsets.push(buf.len() as u64);
+                buf.extend(store.encode(version, key, value.unwrap_or_default(), flags, None)?);
+            }
+            Ok((buf, offsets))
+        };
+        let version = self.version;
+        let (mut buf, mut offsets) = encode_all(self, version)?;
+        let start = self.make_room(buf.len() as u64)?;
+        if self.version != version {
+            (buf, offsets) = encode_all(self, self.version)?;
+        }
+        self.f.write_all(&buf)?;
+        self.sync()?;
+
+        let positions: Vec<Position> = offsets
+            .into_iter()
+            .map(|offset| Position::new(start.segment, start.offset + offset))
+            .collect();
+        for ((key, value), position) in writes.iter().zip(&positions) {
+            match value {
+                Some(value) => {
+                    self.index.insert(key.to_vec(), *position);
+                    self.secondary.insert(key, value);
+                }
+                None => {
+                    self.index.remove(key);
+                    self.secondary.remove(key);
+                }
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Counts a write against the sync policy and syncs if it is due.
+    fn wrote(&mut self) -> Result<()> {
+        self.unsynced += 1;
+        let due = match self.options.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::EveryInterval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Hands buffered writes to the OS. Records are written straight to the
+    /// file, so there is nothing held back in the process; this is here so
+    /// callers don't have to know that.
+    pub fn flush(&mut self) -> Result<()> {
+        self.f.flush()?;
+        Ok(())
+    }
+
+    /// Flushes and then syncs the active segment, so everything written so
+    /// far survives a power failure whatever the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.f.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Syncs writes that have waited out `SyncPolicy::EveryInterval`, for
+    /// when no later write comes along to do it. See `SharedKV::new`.
+    pub(crate) fn sync_if_due(&mut self) -> Result<()> {
+        if let SyncPolicy::EveryInterval(interval) = self.options.sync {
+            if self.unsynced > 0 && self.last_sync.elapsed() >= interval {
+                self.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals the active segment and starts a new one.
+    fn roll_over(&mut self) -> Result<()> {
+        let next = Segment::new(&self.path, self.active_id() + 1);
+        let mut f = OpenOptions::new()
+                            .read(true)
+                            .create_new(true)
+                            .append(true)
+                            .open(&next.path)?;
+        record::write_file_header(&mut f, Version::CURRENT)?;
+
+        self.f.sync_all()?;
+        self.f = f;
+        self.version = Version::CURRENT;
+        self.segments.push(Arc::new(next));
+        Ok(())
     }
 
     pub fn find(
         &mut self,
         target: &ByteStr,
-    ) -> io::Result<Option<(u64, ByteString)>> {
-        let mut f = BufReader::new(&mut self.f);
-        let mut found: Option<(u64, ByteString)> = None;
+    ) -> Result<Option<(Position, ByteString)>> {
+        let mut found: Option<(Position, ByteString)> = None;
+        let start = Position::new(self.segments[0].id, 0);
+        let now = record::now_millis();
 
-        loop {
-            let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = ActionKV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv, 
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        },
-                        _ => return Err(err),
-                    }
+        let truncated = self.walk_log(start, |position, record| {
+            if record.kv.key == target {
+                if record.is_tombstone() || record.is_expired(now) {
+                    found = None;
+                } else {
+                    found = Some((position, record.kv.value));
                 }
+            }
+        })?;
+        // 截掉的记录可能还在索引里，照剩下的日志重建
+        if truncated {
+            self.load()?;
+        }
+
+        Ok(found)
+    }
+
+    /// Adds a secondary index called `name`, replacing any index of that
+    /// name, and fills it by reading every live value. `extract` turns a
+    /// value into the terms to look it up by, e.g. one field of a JSON
+    /// document; values it returns no terms for are left out. From then on
+    /// inserts, deletes and `load` keep the index up to date.
+    pub fn add_index<F>(&mut self, name: &str, extract: F) -> Result<()>
+    where
+        F: Fn(&ByteStr) -> Vec<ByteString> + Send + Sync + 'static,
+    {
+        self.secondary.add(name, Arc::new(extract));
+        self.index_values(Some(name))
+    }
+
+    /// The live pairs whose value the index called `name` extracted `term`
+    /// from, in key order. Unlike `find`, only reads the values it returns.
+    pub fn find_by<'a>(&'a self, name: &str, term: &ByteStr) -> Result<Scan<'a>> {
+        let keys = match self.secondary.get(name, term) {
+            Some(keys) => keys,
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no index called {:?}", name),
+            ).into()),
+        };
+        let index = &self.index;
+        let entries = keys.filter_map(move |key| index.get(key).map(|position| (key, position)));
+        Ok(Scan::new(&self.segments, &self.options.codec, Box::new(entries)))
+    }
+
+    /// Feeds every live value to the secondary index called `name`, or to
+    /// all of them.
+    fn index_values(&mut self, name: Option<&str>) -> Result<()> {
+        if self.secondary.is_empty() {
+            return Ok(());
+        }
+        let now = record::now_millis();
+        for (key, position) in self.index.iter() {
+            let record = segment::read_record(&self.segments, *position, &self.options.codec)?;
+            if record.is_expired(now) {
+                continue;
+            }
+            match name {
+                Some(name) => self.secondary.insert_into(name, key, &record.kv.value),
+                None => self.secondary.insert(key, &record.kv.value),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads records from `start` to the end of the log, handing each one to
+    /// `visit` and dealing with bad ones as the recovery policy says. An
+    /// offset before the first record of a segment means "from its start".
+    /// Returns whether the log was truncated, leaving the index stale.
+    fn walk_log<F>(&mut self, start: Position, mut visit: F) -> Result<bool>
+    where
+        F: FnMut(Position, Record),
+    {
+        let policy = self.options.recovery;
+        let mut truncate_at = None;
+
+        for segment in self.segments.iter().filter(|s| s.id >= start.segment) {
+            let (f, version) = segment.reader()?;
+            let offset = if segment.id == start.segment {
+                start.offset.max(version.data_start())
+            } else {
+                version.data_start()
             };
 
-            if kv.key == target {
-                found = Some((position, kv.value));
+            let codec = &self.options.codec;
+            let walked = walk::walk_segment(&mut BufReader::new(f), version, offset, policy, codec, |offset, record, _| {
+                visit(Position::new(segment.id, offset), record);
+                Ok(())
+            })?;
+
+            if let Some(offset) = walked.truncate_at {
+                truncate_at = Some(Position::new(segment.id, offset));
+                break;
             }
         }
 
-        Ok(found)
+        if let Some(position) = truncate_at {
+            self.truncate_log(position)?;
+        }
+
+        Ok(truncate_at.is_some())
+    }
+
+    /// Drops everything from `position` on: the rest of that segment and
+    /// every later one. The segment that was cut becomes the active one.
+    fn truncate_log(&mut self, position: Position) -> Result<()> {
+        self.writable()?;
+        while self.active_id() > position.segment {
+            let segment = self.segments.pop().unwrap();
+            std::fs::remove_file(&segment.path)?;
+        }
+        compact::sync_dir(&self.path)?;
+
+        let active = self.segments.last().unwrap();
+        self.f = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .open(&active.path)?;
+        self.version = active.reader()?.1;
+        self.f.set_len(position.offset)?;
+        #[cfg(feature = "mmap")]
+        active.map.forget();
+        self.f.sync_all()?;
+        Ok(())
+    }
+
+    /// Live keys in key order. Cheap with an ordered index; a hash index
+    /// sorts them first. Comes straight from the index, so a key whose TTL
+    /// has run out may still be listed; see `insert_with_ttl`.
+    pub fn keys(&self) -> impl Iterator<Item = &ByteStr> + '_ {
+        self.index
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .map(|(key, _)| key.as_slice())
+    }
+
+    /// Every live pair in key order, read from disk as the iterator advances.
+    pub fn iter(&self) -> Scan<'_> {
+        Scan::new(&self.segments, &self.options.codec, self.index.range((Bound::Unbounded, Bound::Unbounded)))
+    }
+
+    /// Pairs whose key falls in `range`, in key order, e.g.
+    /// `store.range("user:a".."user:m")`.
+    pub fn range<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<ByteStr>,
+        R: RangeBounds<K>,
+    {
+        Scan::new(&self.segments, &self.options.codec, self.index.range(bounds(&range)))
+    }
+
+    /// Pairs whose key starts with `prefix`, in key order.
+    pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Scan<'a> {
+        Scan::new(&self.segments, &self.options.codec, self.index.prefix(prefix))
+    }
+
+    /// A consistent read-only view of the store as it is now. Writes made
+    /// afterwards, and compactions, don't show up in it.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let end = Position::new(self.active_id(), self.f.metadata()?.len());
+        Snapshot::new(end, self.segments.clone(), self.options.codec.clone(), self.index.clone())
+    }
+
+    /// Writes a compacted copy of the store to `path`, which then opens as
+    /// a store of its own. See `Snapshot::export_to`.
+    pub fn export_to(&self, path: &Path) -> Result<()> {
+        self.snapshot()?.export_to(path)
+    }
+
+    /// Follows the log from `from`: every put and delete written at or
+    /// after it, including ones not written yet. Start from `end` to only
+    /// see new writes, or from `Position::default()` to replay the whole
+    /// log first. See `Subscription`.
+    pub fn subscribe(&self, from: Position) -> Subscription {
+        Subscription::new(&self.path, self.options.codec.clone(), from)
+    }
+
+    /// Rewrites the log so it only holds live records, then swaps it in.
+    /// Blocks for the whole rewrite; see `start_compaction` for the version
+    /// that doesn't.
+    pub fn compact(&mut self) -> Result<()> {
+        let mut compaction = self.start_compaction()?;
+        compaction.run()?;
+        self.finish_compaction(compaction)
+    }
+
+    /// Takes what compaction needs from the store. Call `Compaction::run`
+    /// without holding on to the store, then hand it back to
+    /// `finish_compaction`.
+    ///
+    /// Only one compaction runs at a time: until the `Compaction` is
+    /// finished or dropped, starting another fails with an `AlreadyExists`
+    /// I/O error.
+    pub fn start_compaction(&mut self) -> Result<Compaction> {
+        self.writable()?;
+        let running = self.compacting.start(&self.path)?;
+        // 有写者锁、又没有别的压缩在跑，剩下的 .compact 只能是上次崩溃留下的
+        compact::remove_leftover(&self.path)?;
+        let end = self.end()?;
+        Ok(Compaction::new(&self.path, running, end, &self.index, self.options.codec.clone()))
+    }
+
+    /// Copies whatever was written since `start_compaction` and swaps the
+    /// compacted file in as the only segment.
+    pub fn finish_compaction(&mut self, mut compaction: Compaction) -> Result<()> {
+        if !self.compacting.owns(&compaction.running) || compaction.started_at > self.end()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compaction was started on a different log",
+            ).into());
+        }
+
+        let replaced = self.segment_ids();
+        let index = compaction.catch_up(&replaced)?;
+
+        // hint 里的位置指向旧文件，先删掉
+        hint::remove(&self.path)?;
+        let target = self.active_id() + 1;
+        compaction.swap(target, &replaced)?;
+
+        let active = Segment::new(&self.path, target);
+        self.f = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .open(&active.path)?;
+        self.version = Version::CURRENT;
+        self.segments = vec![Arc::new(active)];
+        let mut new_index = Index::new(self.options.ordered_index);
+        for (key, offset) in index {
+            new_index.insert(key, Position::new(target, offset));
+        }
+        self.index = new_index;
+
+        Ok(())
     }
 
     #[inline]
-    pub fn update( &mut self, key: &ByteStr, value: &ByteStr) -> io::Result< () > {
+    pub fn update( &mut self, key: &ByteStr, value: &ByteStr) -> Result< () > {
         self.insert(key, value)
     }
 
-    #[inline]
-    pub fn delete( &mut self, key: &ByteStr) -> io::Result< () > {
-        self.insert(key, b"")
+    /// Appends a tombstone. v1 logs have no flags, so there the tombstone
+    /// is the old empty value.
+    pub fn delete( &mut self, key: &ByteStr) -> Result< () > {
+        self.append(key, b"", FLAG_TOMBSTONE, None)?;
+        self.index.remove(key);
+        self.secondary.remove(key);
+        Ok( () )
+    }
+
+}
+
+
+/// `range` as the index takes it.
+fn bounds<'a, K, R>(range: &'a R) -> (Bound<&'a ByteStr>, Bound<&'a ByteStr>)
+where
+    K: AsRef<ByteStr> + 'a,
+    R: RangeBounds<K>,
+{
+    (
+        range.start_bound().map(|k| k.as_ref()),
+        range.end_bound().map(|k| k.as_ref()),
+    )
+}
+
+/// Lazily reads the pairs an index scan turns up, skipping ones that have
+/// expired. See `ActionKV::range`.
+pub struct Scan<'a> {
+    segments: &'a [Arc<Segment>],
+    codec: &'a Codec,
+    entries: Box<dyn Iterator<Item = (&'a ByteString, &'a Position)> + 'a>,
+}
+
+impl<'a> Scan<'a> {
+    fn new(
+        segments: &'a [Arc<Segment>],
+        codec: &'a Codec,
+        entries: Box<dyn Iterator<Item = (&'a ByteString, &'a Position)> + 'a>,
+    ) -> Self {
+        Scan { segments, codec, entries }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (_, position) = self.entries.next()?;
+            match segment::read_record(self.segments, *position, self.codec) {
+                Ok(record) if record.is_expired(record::now_millis()) => continue,
+                Ok(record) => return Some(Ok(record.kv)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("actionkv-tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}-{}", name, std::process::id()));
+        for id in segment::list_segments(&path).unwrap() {
+            fs::remove_file(segment::segment_path(&path, id)).unwrap();
+        }
+        let _ = fs::remove_file(hint::hint_path(&path));
+        let _ = fs::remove_file(replication::replica_path(&path));
+        path
+    }
+
+    fn store_size(store: &ActionKV) -> u64 {
+        store.segments.iter().map(|s| fs::metadata(&s.path).unwrap().len()).sum()
     }
 
+    #[test]
+    fn load_uses_hint_and_scans_the_tail() {
+        let path = temp_path("hint-tail");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.write_hint().unwrap();
+            store.insert(b"a", b"3").unwrap();
+            store.insert(b"c", b"4").unwrap();
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 3);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn corrupt_or_stale_hint_falls_back_to_a_full_scan() {
+        let path = temp_path("hint-corrupt");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.write_hint().unwrap();
+        }
+
+        let mut bytes = fs::read(hint::hint_path(&path)).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(hint::hint_path(&path), &bytes).unwrap();
 
-    /// bitcask format:
-    /// checksum | key_len | value_len | key           | value          |
-    /// u32      | u32     | u32       | [u8; key_len] | [u8; value_len]|
-    fn process_record<R: Read> (
-        f: &mut R
-    ) -> io::Result<KeyValuePair> {
-        let saved_checksum = f.read_u32::<LittleEndian>()?;
-        let key_len = f.read_u32::<LittleEndian>()?;
-        let val_len = f.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
 
-        let mut data = ByteString::with_capacity(data_len as usize);
+        // 日志比 hint 记录的更短：hint 已过期
+        store.insert(b"b", b"2").unwrap();
+        store.write_hint().unwrap();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
 
-        // 清理中间变量？
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(store.index.is_empty());
+    }
+
+    #[test]
+    fn hint_with_a_huge_count_is_ignored() {
+        let path = temp_path("hint-count");
         {
-            f.by_ref()
-              .take(data_len as u64)// take产生一个新的read对象
-              .read_to_end(&mut data)?;
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.write_hint().unwrap();
         }
 
-        debug_assert_eq!(data.len(), data_len as usize);
-        
-        let checksum = crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!(
-                "data corruption encountered ({:08x} != {:08x})",
-                checksum, saved_checksum
-            );
+        // 校验和是对的，只有条数是假的：checksum、magic、end、tail 之后是 count
+        let mut bytes = fs::read(hint::hint_path(&path)).unwrap();
+        bytes[28..36].copy_from_slice(&u64::MAX.to_le_bytes());
+        let checksum = crc::crc32::checksum_ieee(&bytes[4..]);
+        bytes[..4].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(hint::hint_path(&path), &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn stale_hint_stays_stale_after_the_log_grows_back() {
+        let path = temp_path("hint-regrow");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.write_hint().unwrap();
         }
 
-        let value = data.split_off(key_len as usize);
-        let key = data;
+        // 像掉电丢了没 sync 的记录：日志只剩文件头，之后又写过了 hint 的 end
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(Version::CURRENT.data_start())
+            .unwrap();
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"x", b"10").unwrap();
+            store.insert(b"y", b"20").unwrap();
+            store.insert(b"z", b"30").unwrap();
+        }
 
-        Ok( KeyValuePair{ key, value } )
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(!hint::hint_path(&path).exists());
+        assert_eq!(store.index.len(), 3);
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"x").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(store.get(b"z").unwrap(), Some(b"30".to_vec()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn deleted_keys_are_gone_and_empty_values_are_not() {
+        let path = temp_path("tombstone");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            assert_eq!(store.version(), Version::CURRENT);
+            store.insert(b"gone", b"1").unwrap();
+            store.insert(b"empty", b"").unwrap();
+            store.delete(b"gone").unwrap();
+            assert_eq!(store.get(b"gone").unwrap(), None);
+            assert_eq!(store.get(b"empty").unwrap(), Some(vec![]));
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"gone").unwrap(), None);
+        assert_eq!(store.get(b"empty").unwrap(), Some(vec![]));
+        assert_eq!(store.find(b"gone").unwrap(), None);
+        assert!(store.find(b"empty").unwrap().is_some());
+    }
+
+    #[test]
+    fn v1_logs_still_open() {
+        let path = temp_path("v1");
+        let mut bytes = Vec::new();
+        bytes.extend(record::encode(Version::V1, b"a", b"1", 0).unwrap());
+        bytes.extend(record::encode(Version::V1, b"b", b"2", 0).unwrap());
+        bytes.extend(record::encode(Version::V1, b"b", b"", 0).unwrap());
+        fs::write(&path, bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.version(), Version::V1);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+
+        store.insert(b"c", b"3").unwrap();
+        assert_eq!(store.version(), Version::V1);
+        // v1 记录里的空值读回来是墓碑，得换到 v2 段去写
+        store.insert(b"empty", b"").unwrap();
+        assert!(store.version() >= Version::V2);
+        assert_eq!(store.segment_ids().len(), 2);
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"empty").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn compaction_keeps_live_records_and_writes_made_while_it_ran() {
+        let path = temp_path("compact");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..100u32 {
+            store.insert(b"hot", &i.to_le_bytes()).unwrap();
+        }
+        store.insert(b"cold", b"x").unwrap();
+        store.insert(b"doomed", b"y").unwrap();
+        store.delete(b"doomed").unwrap();
+        store.write_hint().unwrap();
+        let before = store_size(&store);
+
+        let mut compaction = store.start_compaction().unwrap();
+        compaction.run().unwrap();
+        store.insert(b"late", b"z").unwrap();
+        store.delete(b"cold").unwrap();
+        store.finish_compaction(compaction).unwrap();
+
+        assert!(store_size(&store) < before);
+        assert_eq!(store.segment_ids(), vec![1]);
+        assert!(!path.exists());
+        assert!(!hint::hint_path(&path).exists());
+        assert_eq!(store.get(b"hot").unwrap(), Some(99u32.to_le_bytes().to_vec()));
+        assert_eq!(store.get(b"cold").unwrap(), None);
+        assert_eq!(store.get(b"doomed").unwrap(), None);
+        assert_eq!(store.get(b"late").unwrap(), Some(b"z".to_vec()));
+
+        store.insert(b"after", b"w").unwrap();
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 3);
+        assert_eq!(store.get(b"after").unwrap(), Some(b"w".to_vec()));
+        assert_eq!(store.get(b"cold").unwrap(), None);
+    }
+
+    #[test]
+    fn only_one_compaction_runs_at_a_time() {
+        let path = temp_path("compact-twice");
+        let shared = SharedKV::open(&path).unwrap();
+        for i in 0..2000u32 {
+            shared.insert(format!("k{}", i % 500).as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+
+        let first = shared.write().start_compaction().unwrap();
+        let err = shared.write().start_compaction().unwrap_err();
+        assert!(matches!(err, ActionKvError::Io(ref e) if e.kind() == io::ErrorKind::AlreadyExists));
+        drop(first);
+
+        // 一次 finish 之后 .compact 已经改名，另一次不能把它认作自己的
+        let mut stale = shared.write().start_compaction().unwrap();
+        stale.run().unwrap();
+        std::fs::remove_file(compact::compact_path(&path)).unwrap();
+        assert!(shared.write().finish_compaction(stale).is_err());
+
+        let results: Vec<Result<()>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2).map(|_| scope.spawn(|| shared.compact())).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(results.iter().any(Result::is_ok));
+        for result in &results {
+            if let Err(err) = result {
+                assert!(matches!(err, ActionKvError::Io(e) if e.kind() == io::ErrorKind::AlreadyExists));
+            }
+        }
+
+        let store = shared.read();
+        assert_eq!(store.index.len(), 500);
+        assert!(store_size(&store) < 500 * 64);
+        assert_eq!(store.get(b"k499").unwrap(), Some(1999u32.to_le_bytes().to_vec()));
+        drop(store);
+
+        drop(shared);
+        let reopened = SharedKV::open(&path).unwrap();
+        assert_eq!(reopened.len(), 500);
+        assert!(!compact::compact_path(&path).exists());
+    }
+
+    /// Three records, with the value of the middle one flipped on disk.
+    fn log_with_corrupt_middle_record(name: &str) -> (PathBuf, Position) {
+        let path = temp_path(name);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let bad = store.insert_but_ignore_index(b"b", b"2").unwrap();
+        let end_of_bad = store.seek_to_end().unwrap() as usize;
+        store.insert(b"c", b"3").unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[end_of_bad - 1] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        (path, bad)
+    }
+
+    #[test]
+    fn corruption_is_an_error_not_a_panic() {
+        let (path, bad) = log_with_corrupt_middle_record("corrupt-fail");
+        let mut store = ActionKV::open(&path).unwrap();
+        match store.load() {
+            Err(ActionKvError::Corruption { offset, .. }) => assert_eq!(offset, bad.offset),
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        assert!(matches!(store.get_at(bad), Err(ActionKvError::Corruption { .. })));
+        assert!(matches!(store.find(b"c"), Err(ActionKvError::Corruption { .. })));
+    }
+
+    #[test]
+    fn checks_report_every_record_and_repair_skips_damage() {
+        let path = temp_path("check");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        let bad = store.insert_but_ignore_index(b"b", b"2").unwrap();
+        let end_of_bad = store.seek_to_end().unwrap() as usize;
+        store.insert(b"c", b"3").unwrap();
+        drop(store);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[end_of_bad - 1] ^= 0xff;
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5]);
+        fs::write(&path, bytes).unwrap();
+
+        let check = Options::new().check(&path).unwrap();
+        assert_eq!(check.live(), 2);
+        let out = temp_path("check-repaired");
+        check.repair_to(&out).unwrap();
+
+        let records: Vec<RecordCheck> = check.map(|rec| rec.unwrap()).collect();
+        let summary: Vec<(Option<&[u8]>, bool)> = records.iter().map(|rec| (rec.key.as_deref(), rec.live)).collect();
+        assert_eq!(summary, vec![
+            (Some(&b"a"[..]), false),
+            (Some(&b"a"[..]), true),
+            (None, false),
+            (Some(&b"c"[..]), true),
+            (None, false),
+        ]);
+        assert_eq!(records[2].position, bad);
+        assert_eq!((records[2].key_len, records[2].value_len), (1, 1));
+        assert!(matches!(records[2].status, RecordStatus::BadChecksum { .. }));
+        assert_eq!(records[4].status, RecordStatus::Torn);
+        assert_eq!(records[4].len, 5);
+
+        let mut repaired = ActionKV::open(&out).unwrap();
+        repaired.load().unwrap();
+        assert_eq!(repaired.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(repaired.get(b"b").unwrap(), None);
+        assert_eq!(repaired.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn recovery_policy_skips_or_truncates_bad_records() {
+        let (path, _) = log_with_corrupt_middle_record("corrupt-skip");
+        let mut store = Options::new().recovery(RecoveryPolicy::Skip).open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        let (path, bad) = log_with_corrupt_middle_record("corrupt-truncate");
+        let mut store = Options::new().recovery(RecoveryPolicy::Truncate).open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), bad.offset);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 1);
+    }
+
+    #[test]
+    fn find_that_truncates_leaves_no_stale_index_entries() {
+        let path = temp_path("corrupt-find-truncate");
+        let mut store = Options::new().recovery(RecoveryPolicy::Truncate).open(&path).unwrap();
+        store.load().unwrap();
+        store.add_index("value", |value| vec![value.to_vec()]).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let bad = store.seek_to_end().unwrap();
+        store.insert(b"a", b"2").unwrap();
+        let end_of_bad = store.seek_to_end().unwrap() as usize;
+        store.insert(b"c", b"3").unwrap();
+
+        // 索引已经指向坏记录后面了，再让 find 截断
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[end_of_bad - 1] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(store.find(b"c").unwrap(), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), bad);
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.find_by("value", b"3").unwrap().count(), 0);
+
+        // 新记录写在截断的位置，旧的偏移不能再指向它
+        store.insert(b"d", b"4").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn get_checks_the_key_of_the_record_it_reads() {
+        let path = temp_path("stale-index");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let a = *store.index.get(b"a").unwrap();
+        store.index.insert(b"b".to_vec(), a);
+        assert!(matches!(store.get(b"b"), Err(ActionKvError::Io(err)) if err.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn torn_writes_are_cut_off_at_every_offset() {
+        let path = temp_path("torn");
+        let mut ends = vec![Version::CURRENT.data_start()];
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            for (i, value) in [&b""[..], b"x", b"a longer value", b"yz"].iter().enumerate() {
+                store.insert(format!("key{}", i).as_bytes(), value).unwrap();
+                ends.push(store.seek_to_end().unwrap());
+            }
+            store.delete(b"key1").unwrap();
+            ends.push(store.seek_to_end().unwrap());
+        }
+        let full = fs::read(&path).unwrap();
+
+        for cut in 0..=full.len() {
+            fs::write(&path, &full[..cut]).unwrap();
+            let mut store = Options::new().repair_torn_tail(true).open(&path).unwrap();
+
+            let complete = ends.iter().filter(|end| **end <= cut as u64).count();
+            let good_end = if complete == 0 { 0 } else { ends[complete - 1] };
+            assert_eq!(store.torn_bytes_dropped(), cut as u64 - good_end, "cut at {}", cut);
+
+            store.load().unwrap();
+            let expected = match complete {
+                0 | 1 => 0,
+                n if n <= 5 => n - 1,
+                _ => 3,
+            };
+            assert_eq!(store.index.len(), expected, "cut at {}", cut);
+
+            store.insert(b"after", b"crash").unwrap();
+            drop(store);
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.get(b"after").unwrap(), Some(b"crash".to_vec()), "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn writes_after_a_torn_tail_survive_a_reopen() {
+        let path = temp_path("torn-append");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let good_end = store.seek_to_end().unwrap();
+        drop(store);
+
+        // 只写了头和两个字节：key 1 字节，value 声称有 100 字节
+        let mut torn = Vec::new();
+        for n in [0u32, 1, 100] {
+            torn.extend_from_slice(&n.to_le_bytes());
+        }
+        torn.extend_from_slice(b"xy");
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn).unwrap();
+
+        match ActionKV::open(&path) {
+            Err(ActionKvError::TornTail { offset, len }) => {
+                assert_eq!((offset, len), (good_end, torn.len() as u64));
+            }
+            other => panic!("expected a torn tail, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_end + torn.len() as u64);
+
+        let mut store = Options::new().repair_torn_tail(true).open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn torn_file_header_is_not_read_as_a_v1_log() {
+        let path = temp_path("torn-header");
+        fs::write(&path, b"ACTIO").unwrap();
+
+        assert!(matches!(ActionKV::open(&path), Err(ActionKvError::TornTail { offset: 0, len: 5 })));
+        assert!(matches!(
+            Options::new().read_only(true).open(&path),
+            Err(ActionKvError::TornTail { offset: 0, len: 5 })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"ACTIO");
+
+        let mut store = Options::new().repair_torn_tail(true).open(&path).unwrap();
+        assert_eq!(store.torn_bytes_dropped(), 5);
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn zero_filled_tail_is_dropped() {
+        let path = temp_path("torn-zeros");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let good_end = store.seek_to_end().unwrap();
+        drop(store);
+
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[0u8; 20]).unwrap();
+
+        let mut store = Options::new().repair_torn_tail(true).open(&path).unwrap();
+        assert_eq!(store.torn_bytes_dropped(), 20);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_end);
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn active_segment_rolls_over_and_old_ones_stay_readable() {
+        let path = temp_path("segments");
+        let mut options = Options::new();
+        options.segment_size(64);
+        {
+            let mut store = options.open(&path).unwrap();
+            for i in 0..20u32 {
+                store.insert(format!("key{}", i % 7).as_bytes(), &i.to_le_bytes()).unwrap();
+            }
+            store.delete(b"key0").unwrap();
+            assert!(store.segment_ids().len() > 1);
+            for segment in &store.segments {
+                assert!(fs::metadata(&segment.path).unwrap().len() <= 64);
+            }
+        }
+
+        for use_hint in [false, true] {
+            let mut store = options.open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.index.len(), 6);
+            assert_eq!(store.get(b"key0").unwrap(), None);
+            assert_eq!(store.get(b"key6").unwrap(), Some(13u32.to_le_bytes().to_vec()));
+            assert_eq!(store.get(b"key5").unwrap(), Some(19u32.to_le_bytes().to_vec()));
+            if use_hint {
+                store.write_hint().unwrap();
+            }
+        }
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.segment_ids().len(), 1);
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 6);
+        assert_eq!(store.get(b"key1").unwrap(), Some(15u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn v1_log_rolls_over_into_a_v2_segment() {
+        let path = temp_path("v1-segments");
+        fs::write(&path, record::encode(Version::V1, b"a", b"1", 0).unwrap()).unwrap();
+
+        let mut store = Options::new().segment_size(16).open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.version(), Version::V1);
+        store.insert(b"b", b"2").unwrap();
+        store.delete(b"a").unwrap();
+        assert_eq!(store.version(), Version::CURRENT);
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn range_and_prefix_scans_come_back_in_key_order() {
+        for ordered in [true, false] {
+            let path = temp_path(&format!("scan-{}", ordered));
+            let mut options = Options::new();
+            options.ordered_index(ordered);
+            {
+                let mut store = options.open(&path).unwrap();
+                for key in ["user:2", "item:1", "user:10", "user:1", "zzz", "user:3"] {
+                    store.insert(key.as_bytes(), key.to_uppercase().as_bytes()).unwrap();
+                }
+                store.delete(b"user:3").unwrap();
+            }
+
+            let mut store = options.open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.index.is_ordered(), ordered);
+
+            let keys: Vec<&ByteStr> = store.keys().collect();
+            assert_eq!(keys, vec![&b"item:1"[..], b"user:1", b"user:10", b"user:2", b"zzz"]);
+
+            let users: Vec<KeyValuePair> = store.prefix(b"user:").map(|kv| kv.unwrap()).collect();
+            let users: Vec<&ByteStr> = users.iter().map(|kv| kv.value.as_slice()).collect();
+            assert_eq!(users, vec![&b"USER:1"[..], b"USER:10", b"USER:2"]);
+
+            let middle: Vec<ByteString> = store
+                .range("j".."user:2")
+                .map(|kv| kv.unwrap().key)
+                .collect();
+            assert_eq!(middle, vec![b"user:1".to_vec(), b"user:10".to_vec()]);
+
+            assert_eq!(store.range(&b"z"[..]..&b"a"[..]).count(), 0);
+            assert_eq!(store.iter().count(), 5);
+        }
+    }
+
+    #[test]
+    fn committed_batches_are_applied_whole() {
+        let path = temp_path("batch-commit");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"gone", b"0").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.insert(b"a", b"1").insert(b"b", b"2").delete(b"gone").insert(b"a", b"3");
+            store.write_batch(&batch).unwrap();
+            assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(store.get(b"gone").unwrap(), None);
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"gone").unwrap(), None);
+
+        store.compact().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn batches_without_a_commit_are_ignored() {
+        let path = temp_path("batch-torn");
+        let (before, after) = {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            let before = store.seek_to_end().unwrap();
+            let mut batch = WriteBatch::new();
+            batch.insert(b"a", b"2").insert(b"b", b"2");
+            store.write_batch(&batch).unwrap();
+            (before, store.seek_to_end().unwrap())
+        };
+
+        // 每一个截断点：batch 要么全在，要么全不在
+        for len in (before..after).rev() {
+            let f = OpenOptions::new().write(true).open(&path).unwrap();
+            f.set_len(len).unwrap();
+            drop(f);
+
+            // 没开修复的话不能接在半个 batch 后面写
+            if len > before {
+                assert!(matches!(ActionKV::open(&path), Err(ActionKvError::TornTail { .. })), "cut at {}", len);
+            }
+            let mut store = Options::new().read_only(true).open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()), "cut at {}", len);
+            assert_eq!(store.get(b"b").unwrap(), None, "cut at {}", len);
+        }
+
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(after - 1).unwrap();
+        let mut options = Options::new();
+        options.repair_torn_tail(true);
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.seek_to_end().unwrap(), before);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn sync_policy_decides_when_writes_are_synced() {
+        let path = temp_path("sync-policy");
+        let mut options = Options::new();
+        options.sync_policy(SyncPolicy::EveryN(3));
+        let mut store = options.open(&path).unwrap();
+
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        assert_eq!(store.unsynced, 2);
+        store.delete(b"a").unwrap();
+        assert_eq!(store.unsynced, 0);
+
+        let mut batch = WriteBatch::new();
+        batch.insert(b"c", b"3").insert(b"d", b"4");
+        store.write_batch(&batch).unwrap();
+        assert_eq!(store.unsynced, 1);
+        store.sync().unwrap();
+        assert_eq!(store.unsynced, 0);
+
+        options.sync_policy(SyncPolicy::Always);
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        store.insert(b"e", b"5").unwrap();
+        assert_eq!(store.unsynced, 0);
+
+        options.sync_policy(SyncPolicy::Never);
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        store.insert(b"f", b"6").unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.unsynced, 1);
+    }
+
+    #[test]
+    fn interval_sync_happens_without_another_write() {
+        let path = temp_path("sync-interval");
+        let interval = Duration::from_millis(200);
+        let store = SharedKV::new(Options::new().sync_policy(SyncPolicy::EveryInterval(interval)).open(&path).unwrap());
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(store.read().unsynced, 1);
+
+        let deadline = Instant::now() + interval * 10;
+        while store.read().unsynced > 0 && Instant::now() < deadline {
+            std::thread::sleep(interval / 4);
+        }
+        assert_eq!(store.read().unsynced, 0);
+    }
+
+    #[test]
+    fn shared_handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<SharedKV>();
+    }
+
+    #[test]
+    fn concurrent_readers_and_writers_match_a_single_thread() {
+        const WRITERS: usize = 4;
+        const READERS: usize = 4;
+        const KEYS: usize = 200;
+
+        fn key(writer: usize, i: usize) -> ByteString {
+            format!("w{}-k{}", writer, i).into_bytes()
+        }
+        // 每个 writer 只碰自己的 key，所以结果和顺序无关
+        fn write_all(store: &SharedKV, writer: usize) {
+            for i in 0..KEYS {
+                store.insert(&key(writer, i), format!("v{}", i).as_bytes()).unwrap();
+                if i % 3 == 0 {
+                    store.insert(&key(writer, i), format!("v{}-again", i).as_bytes()).unwrap();
+                }
+                if i % 7 == 0 {
+                    store.delete(&key(writer, i)).unwrap();
+                }
+            }
+        }
+
+        let mut options = Options::new();
+        options.segment_size(4096);
+
+        let single = SharedKV::open_with(&temp_path("shared-single"), &options).unwrap();
+        for writer in 0..WRITERS {
+            write_all(&single, writer);
+        }
+
+        let path = temp_path("shared-threads");
+        let shared = SharedKV::open_with(&path, &options).unwrap();
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let store = shared.clone();
+                scope.spawn(move || write_all(&store, writer));
+            }
+            for reader in 0..READERS {
+                let store = shared.clone();
+                scope.spawn(move || {
+                    for i in 0..KEYS * 5 {
+                        let writer = (reader + i) % WRITERS;
+                        let value = store.get(&key(writer, i % KEYS)).unwrap();
+                        if let Some(value) = value {
+                            assert!(value.starts_with(format!("v{}", i % KEYS).as_bytes()));
+                        }
+                        if i % 100 == 0 {
+                            let guard = store.read();
+                            for kv in guard.iter() {
+                                kv.unwrap();
+                            }
+                        }
+                    }
+                });
+            }
+            let store = shared.clone();
+            scope.spawn(move || store.compact().unwrap());
+        });
+
+        let expected = single.read();
+        let actual = shared.read();
+        assert_eq!(actual.index.len(), expected.index.len());
+        for (key, _) in expected.index.iter() {
+            assert_eq!(actual.get(key).unwrap(), expected.get(key).unwrap());
+        }
+        drop(actual);
+
+        drop(shared);
+        let reopened = SharedKV::open_with(&path, &options).unwrap();
+        assert_eq!(reopened.len(), expected.index.len());
+        for (key, _) in expected.index.iter() {
+            assert_eq!(reopened.get(key).unwrap(), expected.get(key).unwrap());
+        }
+    }
+
+    fn spawn_server(name: &str) -> (std::net::SocketAddr, SharedKV) {
+        let store = SharedKV::open(&temp_path(name)).unwrap();
+        let server = Server::bind("127.0.0.1:0", store.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+        (addr, store)
+    }
+
+    #[test]
+    fn clients_share_a_store_through_the_server() {
+        let (addr, store) = spawn_server("server");
+        let mut a = Client::connect(addr).unwrap();
+        let mut b = Client::connect(addr).unwrap();
+        a.ping().unwrap();
+
+        a.set(b"user:1", b"alice").unwrap();
+        a.set(b"user:2", b"").unwrap();
+        b.set(b"item:1", b"\r\n$-1\r\n").unwrap();
+        assert_eq!(b.get(b"user:1").unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(b.get(b"user:2").unwrap(), Some(Vec::new()));
+        assert_eq!(a.get(b"item:1").unwrap(), Some(b"\r\n$-1\r\n".to_vec()));
+        assert_eq!(a.get(b"nope").unwrap(), None);
+
+        let users: Vec<ByteString> = b.scan(b"user:").unwrap().into_iter().map(|kv| kv.key).collect();
+        assert_eq!(users, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+        assert_eq!(a.scan(b"").unwrap().len(), 3);
+
+        assert!(b.del(b"user:1").unwrap());
+        assert!(!b.del(b"user:1").unwrap());
+        assert_eq!(a.get(b"user:1").unwrap(), None);
+        assert_eq!(store.get(b"item:1").unwrap(), Some(b"\r\n$-1\r\n".to_vec()));
+    }
+
+    #[test]
+    fn server_answers_bad_requests_with_errors() {
+        let (addr, _store) = spawn_server("server-errors");
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let mut reply = |request: &[u8]| {
+            stream.write_all(request).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+
+        assert!(reply(b"*1\r\n$4\r\nNOPE\r\n").starts_with("-ERR unknown command"));
+        assert!(reply(b"*2\r\n$3\r\nset\r\n$1\r\na\r\n").starts_with("-ERR wrong number"));
+        assert_eq!(reply(b"*3\r\n$3\r\nset\r\n$1\r\na\r\n$1\r\n1\r\n"), "+OK\r\n");
+        assert!(reply(b"GET a\r\n").starts_with("-ERR Protocol error"));
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn read_only_servers_refuse_writes() {
+        let store = SharedKV::open(&temp_path("server-read-only")).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        let mut server = Server::bind("127.0.0.1:0", store.clone()).unwrap();
+        server.read_only(true);
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let mut client = Client::connect(addr).unwrap();
+        assert!(client.set(b"b", b"2").unwrap_err().to_string().contains("READONLY"));
+        assert!(client.del(b"a").unwrap_err().to_string().contains("READONLY"));
+        assert_eq!(client.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(client.scan(b"").unwrap().len(), 1);
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn server_rejects_nested_and_oversized_requests() {
+        let (addr, _store) = spawn_server("server-nested");
+        let reply = |request: &[u8]| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            // 服务端读到第一个嵌套的数组就会断开，后面的可能写不进去
+            let _ = stream.write_all(request);
+            let mut line = String::new();
+            io::BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+
+        // 以前每个 *1 都会递归一层，足够多就把整个进程的栈打爆
+        assert!(reply(&b"*1\r\n".repeat(2_000_000)).starts_with("-ERR Protocol error"));
+        assert!(reply(b"*2\r\n$3\r\nGET\r\n*1\r\n$1\r\na\r\n").starts_with("-ERR Protocol error"));
+        assert!(reply(b"*2\r\n$3\r\nGET\r\n$-1\r\n").starts_with("-ERR Protocol error"));
+        let too_long = format!("*2\r\n$3\r\nGET\r\n${}\r\n", resp::MAX_BULK_LEN + 1);
+        assert!(reply(too_long.as_bytes()).starts_with("-ERR Protocol error"));
+
+        let mut client = Client::connect(addr).unwrap();
+        client.ping().unwrap();
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing_and_are_dropped() {
+        let path = temp_path("ttl");
+        let ttl = Duration::from_millis(100);
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"old", b"kept until overwritten").unwrap();
+            store.insert(b"keep", b"forever").unwrap();
+            store.insert_with_ttl(b"old", b"session", ttl).unwrap();
+            store.insert_with_ttl(b"long", b"lived", Duration::from_secs(3600)).unwrap();
+            store.write_hint().unwrap();
+            assert_eq!(store.get(b"old").unwrap(), Some(b"session".to_vec()));
+            assert_eq!(store.iter().count(), 3);
+        }
+        std::thread::sleep(ttl * 2);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        // hint 里还有这个 key，但 get 和 contains_key 要看过期时间
+        assert!(store.index.contains_key(b"old"));
+        assert!(!store.contains_key(b"old").unwrap());
+        assert!(store.contains_key(b"keep").unwrap());
+        assert!(!KvEngine::contains_key(&store, b"old").unwrap());
+        assert_eq!(store.get(b"old").unwrap(), None);
+        assert_eq!(store.iter().count(), 2);
+        assert_eq!(store.find(b"old").unwrap(), None);
+
+        hint::remove(&path).unwrap();
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(!store.index.contains_key(b"old"));
+        assert_eq!(store.get(b"long").unwrap(), Some(b"lived".to_vec()));
+
+        store.insert_with_ttl(b"brief", b"x", ttl).unwrap();
+        let mut compaction = store.start_compaction().unwrap();
+        compaction.run().unwrap();
+        store.insert_with_ttl(b"brief", b"y", ttl).unwrap();
+        std::thread::sleep(ttl * 2);
+        store.finish_compaction(compaction).unwrap();
+        assert_eq!(store.index.len(), 2);
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"old").unwrap(), None);
+        assert_eq!(store.get(b"brief").unwrap(), None);
+        assert_eq!(store.get(b"keep").unwrap(), Some(b"forever".to_vec()));
+        assert_eq!(store.get(b"long").unwrap(), Some(b"lived".to_vec()));
+    }
+
+    #[test]
+    fn v2_segment_rolls_over_for_a_ttl() {
+        let path = temp_path("ttl-v2");
+        let mut bytes = Vec::new();
+        record::write_file_header(&mut bytes, Version::V2).unwrap();
+        bytes.extend(record::encode(Version::V2, b"a", b"1", 0).unwrap());
+        fs::write(&path, bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(b"c", b"3");
+        store.write_batch(&batch).unwrap();
+        assert_eq!(store.version(), Version::V2);
+
+        store.insert_with_ttl(b"d", b"4", Duration::from_secs(3600)).unwrap();
+        assert_eq!(store.version(), Version::V3);
+        assert_eq!(store.segment_ids(), vec![0, 1]);
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        for (key, value) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3"), (b"d", b"4")] {
+            assert_eq!(store.get(key).unwrap(), Some(value.to_vec()));
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_and_plain_records_share_a_log() {
+        let path = temp_path("lz4");
+        let blob = |i: usize| format!(r#"{{"id":{},"tags":["{}"],"body":"{}"}}"#, i, "x".repeat(20), "lorem ipsum ".repeat(40));
+        let mut lz4 = Options::new();
+        lz4.compression(Compression::Lz4);
+        {
+            let mut store = lz4.open(&path).unwrap();
+            for i in 0..50 {
+                store.insert(format!("doc:{}", i).as_bytes(), blob(i).as_bytes()).unwrap();
+            }
+            store.insert(b"tiny", b"x").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.insert(b"doc:batched", blob(99).as_bytes());
+            store.write_batch(&batch).unwrap();
+            assert!(store_size(&store) * 5 < (blob(0).len() * 50) as u64);
+        }
+        {
+            let mut plain = ActionKV::open(&path).unwrap();
+            plain.load().unwrap();
+            plain.insert(b"doc:plain", blob(7).as_bytes()).unwrap();
+        }
+
+        let mut store = lz4.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"doc:7").unwrap(), Some(blob(7).into_bytes()));
+        assert_eq!(store.get(b"doc:plain").unwrap(), Some(blob(7).into_bytes()));
+        assert_eq!(store.get(b"doc:batched").unwrap(), Some(blob(99).into_bytes()));
+        assert_eq!(store.get(b"tiny").unwrap(), Some(b"x".to_vec()));
+
+        store.compact().unwrap();
+        for i in 0..50 {
+            assert_eq!(store.get(format!("doc:{}", i).as_bytes()).unwrap(), Some(blob(i).into_bytes()));
+        }
+        assert_eq!(store.get(b"doc:plain").unwrap(), Some(blob(7).into_bytes()));
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_records_need_the_feature() {
+        let path = temp_path("lz4-missing");
+        let mut bytes = Vec::new();
+        record::write_file_header(&mut bytes, Version::CURRENT).unwrap();
+        let at = bytes.len() as u64;
+        bytes.extend(record::encode(Version::CURRENT, b"a", b"not really lz4", record::FLAG_LZ4).unwrap());
+        fs::write(&path, bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(ActionKvError::UnsupportedCodec { offset, .. }) if offset == at
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_logs_need_the_right_key() {
+        let path = temp_path("encrypted");
+        let secret = EncryptionKey::new(&[7; 32]);
+        let mut options = Options::new();
+        options.encryption_key(secret.clone());
+        let (at, end) = {
+            let mut store = options.open(&path).unwrap();
+            store.insert(b"ssn:alice", b"078-05-1120").unwrap();
+            let at = store.insert_but_ignore_index(b"ssn:bob", b"219-09-9999").unwrap();
+            let end = store.seek_to_end().unwrap();
+            let mut batch = WriteBatch::new();
+            batch.insert(b"card:alice", b"4111111111111111").delete(b"ssn:bob");
+            store.write_batch(&batch).unwrap();
+            store.insert_with_ttl(b"session:alice", b"token", Duration::from_secs(3600)).unwrap();
+            (at, end)
+        };
+
+        let bytes = fs::read(&path).unwrap();
+        for secret in [&b"alice"[..], b"078-05-1120", b"4111111111111111"] {
+            assert!(!bytes.windows(secret.len()).any(|w| w == secret));
+        }
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"ssn:alice").unwrap(), Some(b"078-05-1120".to_vec()));
+        assert_eq!(store.get(b"card:alice").unwrap(), Some(b"4111111111111111".to_vec()));
+        assert_eq!(store.get(b"session:alice").unwrap(), Some(b"token".to_vec()));
+        assert_eq!(store.get(b"ssn:bob").unwrap(), None);
+
+        drop(store);
+        let mut plain = Options::new().read_only(true).open(&path).unwrap();
+        assert!(matches!(plain.load(), Err(ActionKvError::NoEncryptionKey { .. })));
+
+        let mut wrong = Options::new();
+        wrong.encryption_key(EncryptionKey::new(&[8; 32]));
+        let mut store = wrong.read_only(true).open(&path).unwrap();
+        assert!(matches!(store.load(), Err(ActionKvError::Tampered { offset: 12 })));
+
+        // 改一个字节再把 CRC 算对：CRC 骗过去了，认证骗不过去
+        let mut tampered = bytes.clone();
+        let (start, end) = (at.offset as usize, end as usize);
+        tampered[end - 1] ^= 0x01;
+        let crc = crc::crc32::checksum_ieee(&tampered[start + 12..end]);
+        tampered[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&path, &tampered).unwrap();
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        assert!(matches!(store.load(), Err(ActionKvError::Tampered { offset }) if offset == at.offset));
+
+        tampered[end - 1] ^= 0x02;
+        fs::write(&path, &tampered).unwrap();
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        assert!(matches!(store.load(), Err(ActionKvError::Corruption { .. })));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn checks_of_encrypted_logs_report_tampering_and_missing_keys() {
+        let path = temp_path("check-encrypted");
+        let mut options = Options::new();
+        options.encryption_key(EncryptionKey::new(&[3; 32]));
+        let (b, end_of_b) = {
+            let mut store = options.open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            let b = *store.index.get(&b"b"[..]).unwrap();
+            let end_of_b = store.seek_to_end().unwrap() as usize;
+            store.insert(b"c", b"3").unwrap();
+            (b, end_of_b)
+        };
+
+        // 没有 key：记录都读得出来，只是不知道 key，也不能拿来修复
+        let check = Options::new().check(&path).unwrap();
+        assert!(check.needs_key());
+        assert_eq!(check.live(), 0);
+        assert!(matches!(check.repair_to(&temp_path("check-encrypted-nokey")), Err(ActionKvError::NoEncryptionKey { .. })));
+        let records: Vec<RecordCheck> = check.map(|rec| rec.unwrap()).collect();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|rec| rec.status == RecordStatus::Ok && rec.key.is_none()));
+
+        // 改一个字节再把 CRC 算对，只有认证能发现
+        let mut bytes = fs::read(&path).unwrap();
+        let start = b.offset as usize;
+        bytes[end_of_b - 1] ^= 0x01;
+        let crc = crc::crc32::checksum_ieee(&bytes[start + 12..end_of_b]);
+        bytes[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let check = options.check(&path).unwrap();
+        assert!(!check.needs_key());
+        assert_eq!(check.live(), 2);
+        let out = temp_path("check-encrypted-repaired");
+        check.repair_to(&out).unwrap();
+        let statuses: Vec<RecordStatus> = check.map(|rec| rec.unwrap().status).collect();
+        assert_eq!(statuses, vec![RecordStatus::Ok, RecordStatus::Tampered, RecordStatus::Ok]);
+
+        let mut repaired = options.open(&out).unwrap();
+        repaired.load().unwrap();
+        assert_eq!(repaired.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(repaired.get(b"b").unwrap(), None);
+        assert_eq!(repaired.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn compaction_encrypts_an_existing_plaintext_log() {
+        let path = temp_path("encrypt-existing");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"name", b"plaintext-alice").unwrap();
+            store.insert(b"gone", b"plaintext-bob").unwrap();
+            store.delete(b"gone").unwrap();
+        }
+
+        let mut options = Options::new();
+        options.encryption_key(EncryptionKey::new(&[1; 32]));
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"more", b"plaintext-carol").unwrap();
+        store.compact().unwrap();
+        store.write_hint().unwrap();
+        drop(store);
+
+        let mut files: Vec<PathBuf> = segment::list_segments(&path)
+            .unwrap()
+            .into_iter()
+            .map(|id| segment::segment_path(&path, id))
+            .collect();
+        files.push(hint::hint_path(&path));
+        for file in files {
+            let bytes = fs::read(file).unwrap();
+            assert!(!bytes.windows(9).any(|w| w == b"plaintext"));
+            assert!(!bytes.windows(4).any(|w| w == b"name"));
+        }
+
+        // 没有 key 读不了 hint，只能全量扫描，然后在第一条记录上失败
+        let mut plain = Options::new().read_only(true).open(&path).unwrap();
+        assert!(matches!(plain.load(), Err(ActionKvError::NoEncryptionKey { .. })));
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"name").unwrap(), Some(b"plaintext-alice".to_vec()));
+        assert_eq!(store.get(b"more").unwrap(), Some(b"plaintext-carol".to_vec()));
+    }
+
+    #[test]
+    fn snapshots_stay_put_while_the_store_moves_on() {
+        let path = temp_path("snapshot");
+        let mut options = Options::new();
+        options.segment_size(256);
+        let mut store = options.open(&path).unwrap();
+        for i in 0..20 {
+            store.insert(format!("k{:02}", i).as_bytes(), b"before").unwrap();
+        }
+        store.delete(b"k00").unwrap();
+
+        let snapshot = store.snapshot().unwrap();
+        assert_eq!(snapshot.end(), store.end().unwrap());
+
+        for i in 0..20 {
+            store.insert(format!("k{:02}", i).as_bytes(), b"after").unwrap();
+        }
+        store.insert(b"new", b"after").unwrap();
+        store.delete(b"k01").unwrap();
+        store.compact().unwrap();
+        store.insert(b"k02", b"after compaction").unwrap();
+
+        assert_eq!(snapshot.len(), 19);
+        assert_eq!(snapshot.get(b"k00").unwrap(), None);
+        assert_eq!(snapshot.get(b"k01").unwrap(), Some(b"before".to_vec()));
+        assert_eq!(snapshot.get(b"new").unwrap(), None);
+        let values: Vec<ByteString> = snapshot.iter().map(|kv| kv.unwrap().value).collect();
+        assert_eq!(values, vec![b"before".to_vec(); 19]);
+        assert_eq!(snapshot.prefix(b"k1").count(), 10);
+        assert_eq!(snapshot.range("k05".."k08").count(), 3);
+
+        let backup = temp_path("snapshot-backup");
+        snapshot.export_to(&backup).unwrap();
+        assert!(matches!(
+            snapshot.export_to(&backup),
+            Err(ActionKvError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists
+        ));
+        drop(snapshot);
+
+        let mut copy = ActionKV::open(&backup).unwrap();
+        copy.load().unwrap();
+        assert_eq!(copy.segment_ids(), vec![0]);
+        assert_eq!(copy.index.len(), 19);
+        assert_eq!(copy.get(b"k01").unwrap(), Some(b"before".to_vec()));
+        copy.insert(b"k01", b"independent").unwrap();
+        assert_eq!(store.get(b"k01").unwrap(), None);
+        assert_eq!(store.get(b"k02").unwrap(), Some(b"after compaction".to_vec()));
+    }
+
+    #[test]
+    fn online_backup_while_writers_run() {
+        let path = temp_path("backup-online");
+        let store = SharedKV::open(&path).unwrap();
+        for i in 0..100 {
+            store.insert(format!("k{}", i).as_bytes(), b"0").unwrap();
+        }
+
+        let backup = temp_path("backup-online-copy");
+        std::thread::scope(|scope| {
+            let writer = store.clone();
+            scope.spawn(move || {
+                for round in 1..20 {
+                    for i in 0..100 {
+                        writer.insert(format!("k{}", i).as_bytes(), format!("{}", round).as_bytes()).unwrap();
+                    }
+                }
+            });
+            store.export_to(&backup).unwrap();
+        });
+
+        // writer 按 k0..k99 的顺序一轮轮写，同一时刻的视图只能是
+        // “前面几个 key 是第 n 轮，后面的是第 n-1 轮”
+        let copy = SharedKV::open(&backup).unwrap();
+        assert_eq!(copy.len(), 100);
+        let rounds: Vec<u32> = (0..100)
+            .map(|i| {
+                let value = copy.get(format!("k{}", i).as_bytes()).unwrap().unwrap();
+                String::from_utf8(value).unwrap().parse().unwrap()
+            })
+            .collect();
+        assert!(rounds.windows(2).all(|w| w[0] >= w[1]));
+        assert!(rounds[0] - rounds[99] <= 1);
+    }
+
+    fn drain(sub: &mut Subscription) -> Vec<(ChangeKind, String)> {
+        std::iter::from_fn(|| sub.try_next().unwrap())
+            .map(|change| (change.kind, String::from_utf8(change.kv.key).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn subscriptions_see_writes_in_order_and_resume() {
+        let path = temp_path("subscribe");
+        let mut options = Options::new();
+        options.segment_size(256);
+        let mut store = options.open(&path).unwrap();
+        store.insert(b"before", b"x").unwrap();
+
+        let end = store.end().unwrap();
+        let mut sub = store.subscribe(end);
+        assert!(sub.try_next().unwrap().is_none());
+
+        store.insert(b"a", b"1").unwrap();
+        let a = sub.try_next().unwrap().unwrap();
+        assert_eq!(a.kind, ChangeKind::Put);
+        assert_eq!(a.kv.value, b"1");
+        assert_eq!(sub.position(), a.resume);
+        let saved = sub.position();
+
+        let mut batch = WriteBatch::new();
+        batch.insert(b"b", b"2").delete(b"a");
+        store.write_batch(&batch).unwrap();
+        for i in 0..20 {
+            store.insert(format!("k{:02}", i).as_bytes(), b"rolls the log over").unwrap();
+        }
+        assert!(store.segment_ids().len() > 1);
+
+        let seen = drain(&mut sub);
+        assert_eq!(seen.len(), 22);
+        assert_eq!(seen[0], (ChangeKind::Put, "b".to_string()));
+        assert_eq!(seen[1], (ChangeKind::Delete, "a".to_string()));
+        assert_eq!(seen[21], (ChangeKind::Put, "k19".to_string()));
+        let end = sub.position();
+        drop(sub);
+
+        // 从保存的位置重新订阅，只看到之后的
+        let mut again = store.subscribe(saved);
+        assert_eq!(drain(&mut again), seen);
+
+        let mut from_start = store.subscribe(Position::default());
+        assert_eq!(from_start.try_next().unwrap().unwrap().kv.key, b"before");
+
+        store.delete(b"k00").unwrap();
+        let mut tail = store.subscribe(end);
+        assert_eq!(drain(&mut tail), vec![(ChangeKind::Delete, "k00".to_string())]);
+    }
+
+    #[test]
+    fn subscribers_tail_another_thread() {
+        let path = temp_path("subscribe-tail");
+        let mut options = Options::new();
+        options.segment_size(1024);
+        let store = SharedKV::from(options.open(&path).unwrap());
+        let mut sub = store.subscribe(Position::default());
+        sub.poll_interval(Duration::from_millis(1));
+
+        std::thread::scope(|scope| {
+            let writer = store.clone();
+            scope.spawn(move || {
+                for i in 0..200 {
+                    writer.insert(format!("k{}", i).as_bytes(), b"value").unwrap();
+                }
+            });
+
+            for i in 0..200 {
+                let change = sub.wait_timeout(Duration::from_secs(10)).unwrap().unwrap();
+                assert_eq!(change.kv.key, format!("k{}", i).as_bytes());
+            }
+        });
+        assert!(sub.try_next().unwrap().is_none());
+    }
+
+    fn contents(store: &SharedKV) -> Vec<(ByteString, ByteString)> {
+        store.read().iter().map(|kv| kv.unwrap()).map(|kv| (kv.key, kv.value)).collect()
+    }
+
+    #[test]
+    fn followers_copy_the_leader_and_pick_up_where_they_left_off() {
+        let mut options = Options::new();
+        options.segment_size(512).ordered_index(true);
+        let leader = SharedKV::from(options.open(&temp_path("leader")).unwrap());
+        for i in 0..20 {
+            leader.insert(format!("k{:02}", i).as_bytes(), b"first").unwrap();
+        }
+        leader.insert_with_ttl(b"ttl", b"for a while", Duration::from_secs(3600)).unwrap();
+        leader.delete(b"k00").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(b"k01", b"batched").delete(b"k02");
+        leader.write_batch(&batch).unwrap();
+
+        let server = Leader::bind("127.0.0.1:0", leader.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        let path = temp_path("follower");
+        let replica = SharedKV::from(options.open(&path).unwrap());
+        let mut follower = Follower::new(replica).unwrap();
+        follower.catch_up(addr).unwrap();
+        assert_eq!(contents(follower.store()), contents(&leader));
+        assert!(follower.store().get(b"k00").unwrap().is_none());
+        assert_eq!(follower.store().get(b"k01").unwrap(), Some(b"batched".to_vec()));
+        let position = follower.position();
+        drop(follower);
+
+        for i in 0..20 {
+            leader.insert(format!("k{:02}", i).as_bytes(), b"second").unwrap();
+        }
+        let mut follower = Follower::new(SharedKV::open_with(&path, &options).unwrap()).unwrap();
+        assert_eq!(follower.position(), position);
+        follower.catch_up(addr).unwrap();
+        assert_eq!(contents(follower.store()), contents(&leader));
+        drop(follower);
+
+        // follower 没读到的段被压缩掉了，中间的删除只能靠 reset 补上
+        leader.delete(b"k03").unwrap();
+        for i in 4..20 {
+            leader.insert(format!("k{:02}", i).as_bytes(), b"third").unwrap();
+        }
+        leader.compact().unwrap();
+        let mut follower = Follower::new(SharedKV::open_with(&path, &options).unwrap()).unwrap();
+        follower.catch_up(addr).unwrap();
+        assert!(follower.store().get(b"k03").unwrap().is_none());
+        assert_eq!(contents(follower.store()), contents(&leader));
+    }
+
+    #[test]
+    fn followers_check_what_they_are_sent() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut hello = [0u8; 20];
+            stream.read_exact(&mut hello).unwrap();
+
+            let mut record = record::encode(Version::V3, b"key", b"value", 0).unwrap();
+            let last = record.len() - 1;
+            record[last] ^= 1;
+            let mut frame = vec![b'C'];
+            frame.extend_from_slice(&[0; 12]);
+            frame.extend_from_slice(&1u32.to_le_bytes());
+            frame.extend_from_slice(&[0; 8]);
+            frame.extend(record);
+            stream.write_all(&frame).unwrap();
+        });
+
+        let replica = SharedKV::from(ActionKV::open(&temp_path("follower-crc")).unwrap());
+        let mut follower = Follower::new(replica).unwrap();
+        assert!(matches!(follower.catch_up(addr), Err(ActionKvError::Corruption { .. })));
+        assert!(follower.store().is_empty());
+        assert_eq!(follower.position(), Position::default());
+    }
+
+    /// Indexes values like `city=paris;age=30` by their `city`.
+    fn city(value: &ByteStr) -> Vec<ByteString> {
+        value
+            .split(|b| *b == b';')
+            .filter_map(|field| field.strip_prefix(b"city="))
+            .map(|city| city.to_vec())
+            .collect()
+    }
+
+    fn keys_by_city(store: &ActionKV, term: &str) -> Vec<ByteString> {
+        store.find_by("city", term.as_bytes()).unwrap().map(|kv| kv.unwrap().key).collect()
+    }
+
+    #[test]
+    fn secondary_indexes_follow_writes_and_are_rebuilt_on_load() {
+        let path = temp_path("secondary");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"ann", b"city=paris;age=30").unwrap();
+        store.add_index("city", city).unwrap();
+        store.insert(b"bob", b"city=oslo;age=41").unwrap();
+        store.insert(b"cat", b"city=paris").unwrap();
+        store.insert(b"dan", b"age=7").unwrap();
+        assert_eq!(keys_by_city(&store, "paris"), vec![b"ann".to_vec(), b"cat".to_vec()]);
+        assert_eq!(keys_by_city(&store, "oslo"), vec![b"bob".to_vec()]);
+
+        store.update(b"ann", b"city=oslo").unwrap();
+        store.delete(b"bob").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(b"dan", b"city=paris").delete(b"cat");
+        store.write_batch(&batch).unwrap();
+        store.insert_with_ttl(b"eve", b"city=paris", Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(keys_by_city(&store, "paris"), vec![b"dan".to_vec()]);
+        assert_eq!(keys_by_city(&store, "oslo"), vec![b"ann".to_vec()]);
+        assert!(keys_by_city(&store, "rome").is_empty());
+        assert!(matches!(store.find_by("age", b"30"), Err(ActionKvError::Io(_))));
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.add_index("city", city).unwrap();
+        store.load().unwrap();
+        assert_eq!(keys_by_city(&store, "paris"), vec![b"dan".to_vec()]);
+        assert_eq!(keys_by_city(&store, "oslo"), vec![b"ann".to_vec()]);
+
+        drop(store);
+        let shared = SharedKV::open(&path).unwrap();
+        shared.add_index("city", city).unwrap();
+        let found = shared.find_by("city", b"oslo").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].value, b"city=oslo");
+    }
+
+    /// Run by `writers_lock_out_other_processes` in a child process: holds
+    /// the store at `$AKV_LOCK_HOLDER` open until its stdin is closed.
+    #[test]
+    fn lock_holder() {
+        let path = match std::env::var_os("AKV_LOCK_HOLDER") {
+            Some(path) => PathBuf::from(path),
+            None => return,
+        };
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"held", b"by the child").unwrap();
+        println!("AKV_LOCK_HELD");
+        io::stdout().flush().unwrap();
+        let _ = io::stdin().read(&mut [0u8; 1]);
+    }
+
+    #[test]
+    fn writers_lock_out_other_processes() {
+        use std::process::{Command, Stdio};
+
+        let path = temp_path("lock");
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::lock_holder", "--nocapture", "--test-threads=1"])
+            .env("AKV_LOCK_HOLDER", &path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = io::BufReader::new(child.stdout.take().unwrap()).lines();
+        assert!(lines.any(|line| line.unwrap().contains("AKV_LOCK_HELD")));
+
+        assert!(matches!(ActionKV::open(&path), Err(ActionKvError::StoreLocked { .. })));
+        assert_eq!(ActionKV::open(&path).unwrap_err().exit_code(), exit::LOCKED);
+        let mut reader = Options::new().read_only(true).open(&path).unwrap();
+        reader.load().unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get(b"held").unwrap(), Some(b"by the child".to_vec()));
+        assert!(matches!(reader.insert(b"a", b"1"), Err(ActionKvError::Io(_))));
+        assert!(matches!(reader.write_hint(), Err(ActionKvError::Io(_))));
+
+        // 关掉 stdin，子进程退出，锁也跟着没了
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        assert!(matches!(ActionKV::open(&path), Err(ActionKvError::StoreLocked { .. })));
+        drop(store);
+        ActionKV::open(&path).unwrap();
+        assert!(matches!(
+            Options::new().read_only(true).open(&temp_path("lock-missing")),
+            Err(ActionKvError::Io(err)) if err.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        name: String,
+        balance: i64,
+        tags: Vec<String>,
+    }
+
+    fn typed_round_trip(format: Format) {
+        let path = temp_path(&format!("typed-{:?}", format));
+        let mut store: TypedStore<(String, u32), Account> = TypedStore::new(ActionKV::open(&path).unwrap(), format);
+        let alice = Account { name: "alice".into(), balance: -20, tags: vec!["new".into()] };
+        let bob = Account { name: "bob".into(), balance: 300, tags: vec![] };
+        store.insert(&("eu".into(), 1), &alice).unwrap();
+        store.insert(&("us".into(), 2), &bob).unwrap();
+        store.insert(&("us".into(), 3), &bob).unwrap();
+        store.delete(&("us".into(), 3)).unwrap();
+
+        assert_eq!(store.get(&("eu".into(), 1)).unwrap(), Some(alice));
+        assert_eq!(store.get(&("eu".into(), 2)).unwrap(), None);
+        assert!(!store.contains_key(&("us".into(), 3)).unwrap());
+        drop(store);
+
+        // 同一种格式重新打开，读出来还是那些类型
+        let mut raw = ActionKV::open(&path).unwrap();
+        raw.load().unwrap();
+        let store: TypedStore<(String, u32), Account> = TypedStore::new(raw, format);
+        let pairs: Vec<_> = store.iter().collect::<Result<_>>().unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs.iter().find(|(key, _)| key.1 == 2).unwrap().1, bob);
+        assert_eq!(store.format(), format);
+
+        // 类型对不上是 Serialization 错误，不是 panic
+        let wrong: TypedStore<(String, u32), String> = TypedStore::new(store.into_inner(), format);
+        assert!(matches!(
+            wrong.get(&("us".into(), 2)),
+            Err(ActionKvError::Serialization { format: f, .. }) if f == format
+        ));
+    }
+
+    #[test]
+    fn typed_stores_round_trip_with_bincode() {
+        typed_round_trip(Format::Bincode);
+        // 默认格式就是 bincode，编码和 bincode 本身一致
+        assert_eq!(Format::default().encode(&7u32).unwrap(), bincode::serialize(&7u32).unwrap());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn typed_stores_round_trip_with_json() {
+        typed_round_trip(Format::Json);
+        assert_eq!(Format::Json.encode(&("a", 1)).unwrap(), br#"["a",1]"#.to_vec());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn typed_stores_round_trip_with_cbor() {
+        typed_round_trip(Format::Cbor);
+        assert_eq!(Format::Cbor.decode::<u8>(&[0x18, 0x2a]).unwrap(), 42);
+    }
+
+    /// The same steps, whatever the engine.
+    fn exercise_engine<E: KvEngine>(engine: &mut E) {
+        engine.put(b"b", b"2").unwrap();
+        engine.put(b"a", b"1").unwrap();
+        engine.put(b"c", b"3").unwrap();
+        engine.put(b"a", b"one").unwrap();
+        engine.delete(b"c").unwrap();
+        engine.delete(b"missing").unwrap();
+        engine.flush().unwrap();
+
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(engine.get(b"c").unwrap(), None);
+        assert!(engine.contains_key(b"b").unwrap());
+        let keys = |range| -> Vec<ByteString> { engine.scan(range).map(|kv| kv.unwrap().key).collect() };
+        assert_eq!(keys((Bound::Unbounded, Bound::Unbounded)), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(keys((Bound::Excluded(&b"a"[..]), Bound::Unbounded)), vec![b"b".to_vec()]);
+        assert!(keys((Bound::Included(&b"b"[..]), Bound::Excluded(&b"a"[..]))).is_empty());
+    }
+
+    #[test]
+    fn disk_and_memory_engines_behave_the_same() {
+        exercise_engine(&mut MemKV::new());
+        let mut store = ActionKV::open(&temp_path("engine")).unwrap();
+        exercise_engine(&mut store);
+
+        // 拷一份到内存里，和磁盘上的内容一样
+        let copy = MemKV::copy_of(&store).unwrap();
+        assert_eq!(copy.len(), 2);
+        assert_eq!(KvEngine::get(&copy, b"b").unwrap(), Some(b"2".to_vec()));
+        let engines: Vec<Box<dyn KvEngine>> = vec![Box::new(copy), Box::new(store)];
+        for engine in &engines {
+            assert_eq!(engine.scan((Bound::Unbounded, Bound::Unbounded)).count(), 2);
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_values_are_read_in_place_and_follow_the_log() {
+        let path = temp_path("mmap");
+        let mut store = Options::new().mmap(true).segment_size(4096).open(&path).unwrap();
+        store.insert(b"small", b"value").unwrap();
+        // 活动段还在写，可能被截短，不映射
+        let active = store.get_ref(b"small").unwrap().unwrap();
+        assert!(!active.is_mapped());
+        assert_eq!(&*active, b"value");
+
+        for i in 0..200u32 {
+            store.insert(format!("key{}", i).as_bytes(), &[i as u8; 64]).unwrap();
+            assert_eq!(&*store.get_ref(format!("key{}", i).as_bytes()).unwrap().unwrap(), &[i as u8; 64][..]);
+        }
+        assert!(store.segment_ids().len() > 1);
+        let first = store.get_ref(b"small").unwrap().unwrap();
+        assert!(first.is_mapped());
+        assert_eq!(&*first, b"value");
+
+        // 压缩掉旧段，之前拿到的 value 还在
+        store.delete(b"key0").unwrap();
+        store.compact().unwrap();
+        assert_eq!(&*first, b"value");
+        assert_eq!(store.get_ref(b"key0").unwrap().map(ValueRef::into_vec), None);
+        assert_eq!(store.get(b"key7").unwrap(), Some(vec![7; 64]));
+        let position = *store.index.get(&b"key9"[..]).unwrap();
+        assert_eq!(store.get_at(position).unwrap().value, vec![9; 64]);
+
+        store.insert_with_ttl(b"brief", b"x", Duration::ZERO).unwrap();
+        assert!(store.get_ref(b"brief").unwrap().is_none());
+        drop(store);
+
+        // 不开 mmap 的话是读出来的副本
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let copied = store.get_ref(b"small").unwrap().unwrap();
+        assert!(!copied.is_mapped());
+        assert_eq!(copied.into_vec(), b"value".to_vec());
+
+        drop(store);
+
+        // 映射里读到的坏字节一样会被校验出来
+        let mut store = Options::new().mmap(true).segment_size(4096).open(&path).unwrap();
+        store.load().unwrap();
+        for i in 0..100u32 {
+            store.insert(format!("more{}", i).as_bytes(), &[0; 64]).unwrap();
+        }
+        let position = *store.index.get(&b"small"[..]).unwrap();
+        assert!(store.get_ref(b"small").unwrap().unwrap().is_mapped());
+        let mut f = fs::OpenOptions::new().write(true).open(segment::segment_path(&path, position.segment)).unwrap();
+        f.seek(SeekFrom::Start(position.offset + 25)).unwrap();
+        f.write_all(b"V").unwrap();
+        assert!(matches!(store.get_ref(b"small"), Err(ActionKvError::Corruption { .. })));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn only_sealed_segments_are_mapped() {
+        // 不分段的话只有一个活动段，什么都不会映射
+        let path = temp_path("mmap-one-segment");
+        let mut store = Options::new().mmap(true).open(&path).unwrap();
+        for i in 0..200u32 {
+            store.insert(format!("key{}", i).as_bytes(), &[i as u8; 64]).unwrap();
+        }
+        assert_eq!(store.segment_ids().len(), 1);
+        assert!(!store.get_ref(b"key0").unwrap().unwrap().is_mapped());
+
+        let path = temp_path("mmap-segments");
+        let mut store = Options::new().mmap(true).segment_size(1024).open(&path).unwrap();
+        store.insert(b"key0", &[0; 64]).unwrap();
+        assert!(!store.get_ref(b"key0").unwrap().unwrap().is_mapped());
+        while store.segment_ids().len() == 1 {
+            store.insert(b"filler", &[1; 64]).unwrap();
+        }
+        assert!(store.get_ref(b"key0").unwrap().unwrap().is_mapped());
+        assert!(!store.get_ref(b"filler").unwrap().unwrap().is_mapped());
+    }
+
+    #[test]
+    fn group_commit_acknowledges_every_writer() {
+        let path = temp_path("group");
+        let shared = SharedKV::open_with(&path, Options::new().segment_size(16 * 1024)).unwrap();
+        let group = shared.group_commit();
+
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let group = group.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .map(|i| group.insert(format!("{}-{}", t, i).as_bytes(), &[t as u8; 100]).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut positions: Vec<Position> = writers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+        group.delete(b"0-0").unwrap();
+
+        // 每个写者拿到的都是自己那条记录的位置
+        let total = positions.len();
+        positions.sort();
+        positions.dedup();
+        assert_eq!(positions.len(), total);
+        assert_eq!(shared.read().get_at(*shared.read().index.get(&b"3-7"[..]).unwrap()).unwrap().value, vec![3; 100]);
+        assert!(shared.read().segment_ids().len() > 1);
+        drop(group);
+        drop(shared);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 799);
+        assert_eq!(store.get(b"7-99").unwrap(), Some(vec![7; 100]));
+        assert_eq!(store.get(b"0-0").unwrap(), None);
+        drop(store);
+
+        // 写不了的话同组的每个写者都会拿到错误
+        let reader = SharedKV::open_with(&path, Options::new().read_only(true)).unwrap();
+        let group = reader.group_commit();
+        let err = group.insert(b"a", b"1").unwrap_err();
+        assert!(matches!(err, ActionKvError::Io(err) if err.kind() == io::ErrorKind::PermissionDenied));
+    }
+}
@@ -0,0 +1,34 @@
+//! One writer per store.
+//!
+//! A store opened for writing holds an exclusive advisory lock (`flock` on
+//! Unix) on `FILE.lock` until it is dropped. Read-only opens don't take it,
+//! so they work alongside the writer. The lock goes away with the process
+//! that held it; the file stays behind and means nothing on its own.
+
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+use crate::error::{ActionKvError, Result};
+
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// Takes the writer lock for the store at `path`, without waiting for it.
+pub(crate) fn acquire(path: &Path) -> Result<File> {
+    let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(lock_path(path))?;
+
+    match f.try_lock() {
+        Ok(()) => Ok(f),
+        Err(TryLockError::WouldBlock) => Err(ActionKvError::StoreLocked { path: path.to_path_buf() }),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
@@ -0,0 +1,211 @@
+//! Reading values straight out of memory-mapped segments, with the `mmap`
+//! cargo feature. See `Options::mmap` and `ActionKV::get_ref`.
+//!
+//! Only sealed segments are mapped: they never grow, and nothing but
+//! `RecoveryPolicy::Truncate` cuts them short. The active segment is still
+//! being written to, and torn-tail repair (in this process or in a writer
+//! that opens the store after it) truncates it, so it is read the way
+//! `get` reads it and values from it are copies.
+//!
+//! A sealed segment is mapped the first time a value is read from it.
+//! Mappings are shared and reference counted: a `ValueRef` keeps the one it
+//! points into alive, so compaction doesn't take the bytes away from under
+//! it.
+//!
+//! Records that are compressed or encrypted can't be handed out as they are
+//! stored. Those are decoded into a `ValueRef` that owns its bytes.
+
+use std::fmt;
+use std::ops::Deref;
+#[cfg(feature = "mmap")]
+use std::{fs::File, io, ops::Range, sync::{Arc, PoisonError, RwLock}};
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+#[cfg(feature = "mmap")]
+use crate::codec::Codec;
+#[cfg(feature = "mmap")]
+use crate::error::{ActionKvError, Result};
+#[cfg(feature = "mmap")]
+use crate::record::{self, Record, Version, FLAG_CODECS, FLAG_ENCRYPTED, RECORD_HEADER_LEN};
+#[cfg(feature = "mmap")]
+use crate::segment::{self, Position, Segment};
+use crate::{ByteStr, ByteString};
+
+/// A value read with `ActionKV::get_ref`. Derefs to the value's bytes,
+/// which for a record stored as is in a mapped segment are the segment's
+/// own bytes rather than a copy.
+///
+/// A `load` or `find` with `RecoveryPolicy::Truncate` that cuts the log
+/// back into a sealed segment can leave a mapped `ValueRef` pointing past
+/// the end of its file, and reading it then kills the process with
+/// `SIGBUS`. Don't hold on to one across such a call.
+pub struct ValueRef(Inner);
+
+enum Inner {
+    Owned(ByteString),
+    #[cfg(feature = "mmap")]
+    Mapped { map: Arc<Mmap>, range: Range<usize> },
+}
+
+impl ValueRef {
+    pub(crate) fn owned(value: ByteString) -> Self {
+        ValueRef(Inner::Owned(value))
+    }
+
+    /// Whether the bytes are the mapped segment's rather than a copy.
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.0, Inner::Owned(_))
+    }
+
+    /// The value as a `Vec`, copying it only if it is mapped.
+    pub fn into_vec(self) -> ByteString {
+        match self.0 {
+            Inner::Owned(value) => value,
+            #[cfg(feature = "mmap")]
+            Inner::Mapped { map, range } => map[range].to_vec(),
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = ByteStr;
+
+    fn deref(&self) -> &ByteStr {
+        match &self.0 {
+            Inner::Owned(value) => value,
+            #[cfg(feature = "mmap")]
+            Inner::Mapped { map, range } => &map[range.clone()],
+        }
+    }
+}
+
+impl AsRef<ByteStr> for ValueRef {
+    fn as_ref(&self) -> &ByteStr {
+        self
+    }
+}
+
+impl fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueRef")
+            .field("mapped", &self.is_mapped())
+            .field("value", &&**self)
+            .finish()
+    }
+}
+
+/// The current mapping of one segment, if it has been mapped yet.
+#[cfg(feature = "mmap")]
+#[derive(Default)]
+pub(crate) struct SegmentMap(RwLock<Option<Arc<Mmap>>>);
+
+#[cfg(feature = "mmap")]
+impl SegmentMap {
+    /// A mapping of `f` at least `len` bytes long, or as long as the file
+    /// if it is shorter. Maps the file again if the current mapping is
+    /// shorter than that, which happens when a segment that was cut short
+    /// and written to again is sealed once more.
+    fn covering(&self, f: &File, len: u64) -> io::Result<Arc<Mmap>> {
+        let long_enough = |map: &Option<Arc<Mmap>>| map.as_ref().filter(|map| map.len() as u64 >= len).cloned();
+        if let Some(map) = long_enough(&self.0.read().unwrap_or_else(PoisonError::into_inner)) {
+            return Ok(map);
+        }
+
+        let mut slot = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(map) = long_enough(&slot) {
+            return Ok(map);
+        }
+        // SAFETY: 只映射已经封存的段，它们不再追加，也不会被修复撕裂的尾巴截短；
+        // 剩下 RecoveryPolicy::Truncate 一种情况，见 ValueRef 的说明
+        let map = Arc::new(unsafe { Mmap::map(f)? });
+        *slot = Some(map.clone());
+        Ok(map)
+    }
+
+    /// Drops the mapping, e.g. after the file was cut short. Values already
+    /// handed out keep theirs.
+    pub fn forget(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl fmt::Debug for SegmentMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.0.read().unwrap_or_else(PoisonError::into_inner).as_ref().map(|map| map.len());
+        f.debug_struct("SegmentMap").field("len", &len).finish()
+    }
+}
+
+/// Whether `position` is in a sealed segment, i.e. not the last one.
+#[cfg(feature = "mmap")]
+fn is_sealed(segments: &[Arc<Segment>], position: Position) -> bool {
+    segments.last().is_some_and(|active| position.segment < active.id)
+}
+
+/// The mapping of the sealed segment `position` is in, long enough to hold
+/// the whole record there if the file is.
+#[cfg(feature = "mmap")]
+fn map_record(segments: &[Arc<Segment>], position: Position) -> Result<(Arc<Mmap>, Version)> {
+    let segment = segment::find(segments, position.segment)?;
+    let (f, version) = segment.reader()?;
+    let start = position.offset as usize;
+
+    // 先映射到能读出长度，再按记录长度确保整条都在映射里
+    let map = segment.map.covering(f, position.offset + RECORD_HEADER_LEN)?;
+    let len = map.get(start..).and_then(|bytes| record::record_len(bytes, version));
+    match len {
+        Some(len) if map.len() < start + len as usize => Ok((segment.map.covering(f, position.offset + len)?, version)),
+        _ => Ok((map, version)),
+    }
+}
+
+/// `segment::read_record`, reading from the segment's mapping if it is
+/// sealed.
+#[cfg(feature = "mmap")]
+pub(crate) fn read_record(segments: &[Arc<Segment>], position: Position, codec: &Codec) -> Result<Record> {
+    if !is_sealed(segments, position) {
+        return segment::read_record(segments, position, codec);
+    }
+    let (map, version) = map_record(segments, position)?;
+    let mut bytes = map.get(position.offset as usize..).unwrap_or_default();
+    record::decode(&mut bytes, version, position.offset, codec)
+}
+
+/// `read_record` for the value and expiry only, leaving the value in the
+/// mapping unless it has to be decoded or isn't mapped. Checks the
+/// checksum just the same.
+#[cfg(feature = "mmap")]
+pub(crate) fn read_value(
+    segments: &[Arc<Segment>],
+    position: Position,
+    key: &ByteStr,
+    codec: &Codec,
+) -> Result<(Option<u64>, ValueRef)> {
+    if !is_sealed(segments, position) {
+        let record = segment::read_record(segments, position, codec)?;
+        segment::expect_key(position, key, &record.kv.key)?;
+        return Ok((record.expires_at, ValueRef::owned(record.kv.value)));
+    }
+    let (map, version) = map_record(segments, position)?;
+    let start = position.offset as usize;
+    let bytes = map.get(start..).unwrap_or_default();
+    let raw = record::raw_from_slice(bytes, version)?;
+    let actual = raw.actual_checksum();
+    if actual != raw.checksum {
+        return Err(ActionKvError::Corruption { offset: position.offset, expected: raw.checksum, actual });
+    }
+
+    let expires_at = raw.expires_at(version);
+    if raw.flags(version) & (FLAG_CODECS | FLAG_ENCRYPTED) != 0 {
+        let record = record::decode(&mut &bytes[..], version, position.offset, codec)?;
+        segment::expect_key(position, key, &record.kv.key)?;
+        return Ok((expires_at, ValueRef::owned(record.kv.value)));
+    }
+    segment::expect_key(position, key, raw.key(version))?;
+    let end = start + raw.len() as usize;
+    let range = end - raw.value_len as usize..end;
+    Ok((expires_at, ValueRef(Inner::Mapped { map, range })))
+}
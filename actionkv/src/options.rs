@@ -0,0 +1,165 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::codec::{Codec, Compression};
+#[cfg(feature = "encryption")]
+use crate::crypto::EncryptionKey;
+use crate::error::Result;
+use crate::{ActionKV, LogCheck};
+
+/// What `load` and `find` do when they hit a record whose checksum does not
+/// match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryPolicy {
+    /// Return `ActionKvError::Corruption`.
+    #[default]
+    Fail,
+    /// Step over the bad record and keep reading.
+    Skip,
+    /// Cut the log off at the bad record, dropping it and everything after.
+    /// `find` reloads the index when it cuts the log.
+    Truncate,
+}
+
+/// When writes are forced to disk with `fsync`. Anything not yet synced
+/// can be lost if the machine (not just the process) goes down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Sync after every insert, delete and batch before returning.
+    Always,
+    /// Sync after every `n` writes.
+    EveryN(u32),
+    /// Sync on the first write once this long has passed since the last
+    /// sync. A `SharedKV` also syncs from a background thread once that
+    /// long has passed with writes still unsynced; a lone `ActionKV` that
+    /// stops writing stays unsynced until `ActionKV::sync` or until it is
+    /// dropped.
+    EveryInterval(Duration),
+    /// Leave it to the OS, unless `ActionKV::sync` is called.
+    #[default]
+    Never,
+}
+
+/// Settings for opening a store, in the style of `std::fs::OpenOptions`:
+///
+/// ```no_run
+/// use libactionkv::{Options, RecoveryPolicy};
+///
+/// let mut store = Options::new()
+///     .recovery(RecoveryPolicy::Skip)
+///     .open("data.akv".as_ref())
+///     .unwrap();
+/// store.load().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub(crate) recovery: RecoveryPolicy,
+    pub(crate) repair_torn_tail: bool,
+    pub(crate) segment_size: Option<u64>,
+    pub(crate) ordered_index: bool,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) codec: Codec,
+    pub(crate) read_only: bool,
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap: bool,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    pub fn recovery(&mut self, policy: RecoveryPolicy) -> &mut Self {
+        self.recovery = policy;
+        self
+    }
+
+    /// Cut off a record left half-written at the end of the log by a crash,
+    /// so that new records are appended right after the last good one.
+    /// `ActionKV::torn_bytes_dropped` tells how much was removed.
+    ///
+    /// Opening for writing looks for a torn tail either way, since anything
+    /// appended after one would be lost to the next `load`. Without this
+    /// option, finding one makes `open` fail with `ActionKvError::TornTail`
+    /// and leaves the log as it is.
+    ///
+    /// A file header cut short the same way is checked for on every open,
+    /// read-only ones too. Only a writable open with this option empties
+    /// the log and carries on; otherwise `open` fails.
+    pub fn repair_torn_tail(&mut self, repair: bool) -> &mut Self {
+        self.repair_torn_tail = repair;
+        self
+    }
+
+    /// Start a new segment once the active one would grow past `bytes`.
+    /// Without it the store stays a single file.
+    pub fn segment_size(&mut self, bytes: u64) -> &mut Self {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Keep the index in key order (a `BTreeMap`), so `range`, `prefix` and
+    /// `keys` don't have to sort. Costs a little on every insert.
+    pub fn ordered_index(&mut self, ordered: bool) -> &mut Self {
+        self.ordered_index = ordered;
+        self
+    }
+
+    /// How often writes are synced to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(&mut self, policy: SyncPolicy) -> &mut Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Compress values as they are written. Records already in the log are
+    /// read whatever this says; compaction rewrites them with it.
+    pub fn compression(&mut self, codec: Compression) -> &mut Self {
+        self.codec.compression = codec;
+        self
+    }
+
+    /// Encrypt every record written from now on with `key`, and use it to
+    /// read the ones that already are. See `crypto` for the format.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.codec.key = Some(key);
+        self
+    }
+
+    /// Open without the writer lock, so that it works while another
+    /// process has the store open for writing. Nothing is created or
+    /// repaired, writes fail, and `load` sees what was on disk at the time;
+    /// reopen to catch up with a writer that has since rolled over or
+    /// compacted the log. See `lock`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Read values in sealed segments through memory maps instead of a
+    /// read call per record, so that `ActionKV::get_ref` can hand them out
+    /// without copying. The active segment and scans still read the file.
+    ///
+    /// So it only helps a store with more than one segment. Without
+    /// `segment_size` the whole store is one active segment, and turning
+    /// this on changes nothing.
+    ///
+    /// Only safe as long as nothing cuts a sealed segment short while it is
+    /// mapped: see `ValueRef`. On Windows a mapped segment can't be
+    /// deleted, so compaction fails while values from it are held.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    pub fn open(&self, path: &Path) -> Result<ActionKV> {
+        ActionKV::open_with(path, self.clone())
+    }
+
+    /// Reads the log at `path` record by record, without opening it as a
+    /// store or changing it. Only the encryption key is used.
+    pub fn check(&self, path: &Path) -> Result<LogCheck> {
+        LogCheck::new(path, self.codec.clone())
+    }
+}
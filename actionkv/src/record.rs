@@ -0,0 +1,353 @@
+//! On-disk layout of the log.
+//!
+//! A v2 or v3 log starts with a file header, every record carries a flags
+//! byte:
+//!
+//! file header:
+//! magic      | version
+//! [u8; 8]    | u32
+//!
+//! record (v2):
+//! checksum | key_len | value_len | flags | key           | value           |
+//! u32      | u32     | u32       | u8    | [u8; key_len] | [u8; value_len] |
+//!
+//! The checksum covers flags, key and value.
+//!
+//! v3 adds an expiry time to every record, in milliseconds since the Unix
+//! epoch. 0 means the record never expires:
+//!
+//! record (v3):
+//! checksum | key_len | value_len | flags | expires_at | key           | value           |
+//! u32      | u32     | u32       | u8    | u64        | [u8; key_len] | [u8; value_len] |
+//!
+//! The checksum covers flags, expires_at, key and value. v2 segments stay
+//! v2 until they are compacted; the first write with a TTL rolls a v2
+//! active segment over to a v3 one.
+//!
+//! Logs written before the header existed (v1) start straight with records
+//! and have no flags byte:
+//!
+//! record (v1):
+//! checksum | key_len | value_len | key           | value           |
+//! u32      | u32     | u32       | [u8; key_len] | [u8; value_len] |
+//!
+//! v1 had no way to mark a delete other than writing an empty value, so an
+//! empty value in a v1 log is read back as a tombstone.
+
+use std::io;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::codec::{self, Codec};
+use crate::crypto;
+use crate::error::{ActionKvError, Result};
+use crate::{ByteStr, ByteString, KeyValuePair};
+
+pub(crate) const MAGIC: &[u8; 8] = b"ACTIONKV";
+pub(crate) const FILE_HEADER_LEN: u64 = 12;
+/// Checksum, key length and value length, in front of every record.
+pub(crate) const RECORD_HEADER_LEN: u64 = 12;
+
+/// The record deletes its key.
+pub(crate) const FLAG_TOMBSTONE: u8 = 0x01;
+/// Opens a write batch. Carries no key; the value is the op count.
+pub(crate) const FLAG_BATCH_BEGIN: u8 = 0x02;
+/// Closes a write batch. Carries no key; see `batch` for the value.
+pub(crate) const FLAG_BATCH_COMMIT: u8 = 0x04;
+/// A put or delete that belongs to the batch opened before it.
+pub(crate) const FLAG_BATCHED: u8 = 0x08;
+/// The value is LZ4 compressed. See `codec`.
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+pub(crate) const FLAG_LZ4: u8 = 0x10;
+/// Bits that name the codec a value was compressed with. Room for three.
+pub(crate) const FLAG_CODECS: u8 = 0x30;
+/// Key and value are encrypted into the value field. See `crypto`.
+pub(crate) const FLAG_ENCRYPTED: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V1 = 1,
+    V2 = 2,
+    V3 = 3,
+}
+
+impl Version {
+    pub const CURRENT: Version = Version::V3;
+
+    /// Where the first record starts.
+    pub(crate) fn data_start(self) -> u64 {
+        match self {
+            Version::V1 => 0,
+            Version::V2 | Version::V3 => FILE_HEADER_LEN,
+        }
+    }
+
+    /// Bytes between the lengths and the key: flags, then the expiry.
+    fn extra_len(self) -> u64 {
+        match self {
+            Version::V1 => 0,
+            Version::V2 => 1,
+            Version::V3 => 9,
+        }
+    }
+}
+
+/// Now, in the unit `expires_at` is stored in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn write_file_header<W: Write>(f: &mut W, version: Version) -> io::Result<()> {
+    f.write_all(MAGIC)?;
+    f.write_u32::<LittleEndian>(version as u32)
+}
+
+/// A file shorter than a header that holds nothing but the start of one:
+/// the process died while creating the log.
+pub(crate) fn is_torn_file_header(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && (bytes.len() as u64) < FILE_HEADER_LEN
+        && bytes.iter().zip(MAGIC.iter()).all(|(a, b)| a == b)
+}
+
+/// Reads the file header. Anything that does not start with the magic is a
+/// v1 log. A file that stops partway through the header is
+/// `ActionKvError::TornTail`: no v1 record is that short.
+pub(crate) fn read_file_header<R: Read>(f: &mut R) -> Result<Version> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+    f.by_ref().take(FILE_HEADER_LEN).read_to_end(&mut header)?;
+    if is_torn_file_header(&header) {
+        return Err(ActionKvError::TornTail { offset: 0, len: header.len() as u64 });
+    }
+
+    if header.len() < FILE_HEADER_LEN as usize || &header[..8] != MAGIC {
+        return Ok(Version::V1);
+    }
+
+    let mut version = &header[8..];
+    match version.read_u32::<LittleEndian>()? {
+        2 => Ok(Version::V2),
+        3 => Ok(Version::V3),
+        other => Err(ActionKvError::UnsupportedVersion(other)),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Record {
+    pub checksum: u32,
+    pub flags: u8,
+    /// Milliseconds since the Unix epoch. Only v3 records have one.
+    pub expires_at: Option<u64>,
+    pub kv: KeyValuePair,
+}
+
+impl Record {
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & FLAG_TOMBSTONE != 0
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expires_at, now)
+    }
+}
+
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|at| at <= now)
+}
+
+/// Keys and values are stored with a u32 length.
+pub(crate) fn check_lengths(key: &ByteStr, value: &ByteStr) -> Result<()> {
+    if key.len() > u32::MAX as usize {
+        return Err(ActionKvError::KeyTooLarge { len: key.len() });
+    }
+    if value.len() > u32::MAX as usize {
+        return Err(ActionKvError::ValueTooLarge { len: value.len() });
+    }
+    Ok(())
+}
+
+/// Serializes one record, ready to be appended in a single write.
+pub(crate) fn encode(
+    version: Version,
+    key: &ByteStr,
+    value: &ByteStr,
+    flags: u8,
+) -> Result<ByteString> {
+    encode_expiring(version, key, value, flags, None)
+}
+
+/// `encode` for a record that stops being visible at `expires_at`. Only v3
+/// can store that.
+pub(crate) fn encode_expiring(
+    version: Version,
+    key: &ByteStr,
+    value: &ByteStr,
+    flags: u8,
+    expires_at: Option<u64>,
+) -> Result<ByteString> {
+    debug_assert!(expires_at.is_none() || version >= Version::V3);
+    check_lengths(key, value)?;
+
+    let mut body = ByteString::with_capacity(version.extra_len() as usize + key.len() + value.len());
+    if version >= Version::V2 {
+        body.push(flags);
+    }
+    if version >= Version::V3 {
+        body.write_u64::<LittleEndian>(expires_at.unwrap_or(0))?;
+    }
+    body.extend_from_slice(key);
+    body.extend_from_slice(value);
+
+    let checksum = crc32::checksum_ieee(&body);
+
+    let mut buf = ByteString::with_capacity(12 + body.len());
+    buf.write_u32::<LittleEndian>(checksum)?;
+    buf.write_u32::<LittleEndian>(key.len() as u32)?;
+    buf.write_u32::<LittleEndian>(value.len() as u32)?;
+    buf.write_all(&body)?;
+
+    Ok(buf)
+}
+
+/// A record as it is on disk, before its checksum has been checked. `data`
+/// is a copy read from the file, or borrowed from a mapped segment.
+#[derive(Debug)]
+pub(crate) struct RawRecord<D = ByteString> {
+    pub checksum: u32,
+    pub key_len: u32,
+    pub value_len: u32,
+    /// Everything the checksum covers.
+    pub data: D,
+}
+
+impl<D: AsRef<ByteStr>> RawRecord<D> {
+    pub fn actual_checksum(&self) -> u32 {
+        crc32::checksum_ieee(self.data.as_ref())
+    }
+
+    /// Bytes the record takes up in the log.
+    pub fn len(&self) -> u64 {
+        RECORD_HEADER_LEN + self.data.as_ref().len() as u64
+    }
+
+    pub fn flags(&self, version: Version) -> u8 {
+        match version {
+            Version::V1 if self.value_len == 0 => FLAG_TOMBSTONE,
+            Version::V1 => 0,
+            Version::V2 | Version::V3 => self.data.as_ref()[0],
+        }
+    }
+
+    pub fn expires_at(&self, version: Version) -> Option<u64> {
+        match version {
+            Version::V3 => Some(u64::from_le_bytes(self.data.as_ref()[1..9].try_into().unwrap())).filter(|at| *at != 0),
+            _ => None,
+        }
+    }
+
+    /// The key field. Empty for encrypted records, which keep the key in
+    /// the value.
+    pub fn key(&self, version: Version) -> &ByteStr {
+        let start = version.extra_len() as usize;
+        &self.data.as_ref()[start..start + self.key_len as usize]
+    }
+
+    pub fn value(&self, version: Version) -> &ByteStr {
+        &self.data.as_ref()[version.extra_len() as usize + self.key_len as usize..]
+    }
+}
+
+/// `read_raw` for a record already in memory at the start of `bytes`,
+/// borrowing its fields rather than copying them.
+#[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+pub(crate) fn raw_from_slice(bytes: &ByteStr, version: Version) -> Result<RawRecord<&ByteStr>> {
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "record truncated");
+    let len = record_len(bytes, version).ok_or_else(truncated)?;
+    let data = bytes.get(RECORD_HEADER_LEN as usize..len as usize).ok_or_else(truncated)?;
+    let field = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+    Ok(RawRecord { checksum: field(0), key_len: field(4), value_len: field(8), data })
+}
+
+/// Bytes taken up by the record at the start of `bytes`, going by its
+/// lengths. `None` if `bytes` doesn't hold them.
+#[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+pub(crate) fn record_len(bytes: &ByteStr, version: Version) -> Option<u64> {
+    let head = bytes.get(..RECORD_HEADER_LEN as usize)?;
+    let key_len = u32::from_le_bytes(head[4..8].try_into().unwrap());
+    let value_len = u32::from_le_bytes(head[8..12].try_into().unwrap());
+    Some(RECORD_HEADER_LEN + version.extra_len() + key_len as u64 + value_len as u64)
+}
+
+/// Reads the record that starts at the current position without checking
+/// it. A clean end of file, or a record cut short, comes back as an
+/// `UnexpectedEof` I/O error.
+pub(crate) fn read_raw<R: Read>(f: &mut R, version: Version) -> Result<RawRecord> {
+    let checksum = f.read_u32::<LittleEndian>()?;
+    let key_len = f.read_u32::<LittleEndian>()?;
+    let value_len = f.read_u32::<LittleEndian>()?;
+    let data_len = version.extra_len() + key_len as u64 + value_len as u64;
+
+    // 长度字段本身可能已经损坏，不要照着它预先分配内存
+    let mut data = ByteString::with_capacity(data_len.min(1 << 20) as usize);
+    f.by_ref()
+      .take(data_len)
+      .read_to_end(&mut data)?;
+
+    if data.len() as u64 != data_len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "record truncated",
+        ).into());
+    }
+
+    Ok(RawRecord { checksum, key_len, value_len, data })
+}
+
+/// Reads the record that starts at `offset`. A clean end of file, or a
+/// record cut short, comes back as an `UnexpectedEof` I/O error; a checksum
+/// mismatch as `Corruption`. Encrypted and compressed records come back
+/// as plain ones, with those flags cleared.
+pub(crate) fn decode<R: Read>(
+    f: &mut R,
+    version: Version,
+    offset: u64,
+    codec: &Codec,
+) -> Result<Record> {
+    let raw = read_raw(f, version)?;
+
+    let checksum = raw.actual_checksum();
+    if checksum != raw.checksum {
+        return Err(ActionKvError::Corruption {
+            offset,
+            expected: raw.checksum,
+            actual: checksum,
+        });
+    }
+
+    let mut flags = raw.flags(version);
+    let expires_at = raw.expires_at(version);
+    let mut key = raw.data;
+    let mut value = key.split_off(version.extra_len() as usize + raw.key_len as usize);
+    key.drain(..version.extra_len() as usize);
+
+    if flags & FLAG_ENCRYPTED != 0 {
+        (key, value) = crypto::open(codec.key.as_ref(), flags, expires_at, &value, offset)?;
+        flags &= !FLAG_ENCRYPTED;
+    }
+    if flags & FLAG_CODECS != 0 {
+        value = codec::decompress(flags, value, offset)?;
+        flags &= !FLAG_CODECS;
+    }
+
+    Ok(Record { checksum, flags, expires_at, kv: KeyValuePair { key, value } })
+}
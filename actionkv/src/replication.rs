@@ -0,0 +1,308 @@
+//! Copying a store to followers.
+//!
+//! A `Leader` serves its log over TCP, and each `Follower` applies what it
+//! is sent to a store of its own, which can then serve reads. Writes go to
+//! the leader only: anything written to a follower directly is overwritten
+//! or left behind as the leader's log comes in, so a `Server` in front of
+//! a follower should be `read_only`.
+//!
+//! The follower opens with where to start, and the leader follows its log
+//! from there with a `Subscription`:
+//!
+//! ```text
+//! follower: "AKVREPL1" | segment u32 | offset u64
+//! leader:   'C' | position | resume | record   a put or delete
+//!           'R' | position | resume            a `ChangeKind::Reset`
+//!           'H'                                caught up, for now
+//! ```
+//!
+//! Positions are a segment u32 and an offset u64, little-endian like the
+//! log. Records are in the v3 layout with their CRC, which the follower
+//! checks, and go over the wire neither compressed nor encrypted: the
+//! follower writes them with its own `Options`. The leader repeats `H` every
+//! second while there is nothing to send, so it notices followers that have
+//! gone away.
+//!
+//! **The stream is neither encrypted nor authenticated.** Anyone who can
+//! connect to the leader's address gets every key and value, in plaintext
+//! even when the leader's store has an encryption key, and anything on the
+//! path between leader and follower can read or alter it. Bind the leader
+//! to loopback or a private network, and tunnel it (SSH, a VPN, stunnel)
+//! to reach followers elsewhere.
+//!
+//! A follower keeps the leader position it has reached in `FILE.replica`,
+//! written after each change is applied. If the machine (not just the
+//! process) goes down, it can be ahead of what made it to disk unless the
+//! follower's store uses `SyncPolicy::Always`.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::codec::Codec;
+use crate::error::Result;
+use crate::record::{self, Version, FLAG_TOMBSTONE};
+use crate::{ByteString, Change, ChangeKind, Position, SharedKV, WriteBatch};
+
+const MAGIC: &[u8; 8] = b"AKVREPL1";
+const CHANGE: u8 = b'C';
+const RESET: u8 = b'R';
+const CAUGHT_UP: u8 = b'H';
+
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+pub(crate) fn replica_path(path: &Path) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(".replica");
+    PathBuf::from(name)
+}
+
+pub struct Leader {
+    listener: TcpListener,
+    store: SharedKV,
+}
+
+impl Leader {
+    /// Listens for followers on `addr`. Any client that connects is sent
+    /// the whole store in plaintext: see the module docs before binding to
+    /// anything but loopback.
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: SharedKV) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Leader { listener, store })
+    }
+
+    /// Where the leader is listening. Useful after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts followers until accepting fails, serving each on its own
+    /// thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let store = self.store.clone();
+            thread::spawn(move || {
+                // follower 断开或者出错，只影响它自己
+                let _ = serve(stream, &store);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve(stream: TcpStream, store: &SharedKV) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a follower").into());
+    }
+    let from = read_position(&mut reader)?;
+
+    let mut sub = store.subscribe(from);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        while let Some(change) = sub.try_next()? {
+            write_change(&mut writer, &change)?;
+        }
+        writer.write_u8(CAUGHT_UP)?;
+        writer.flush()?;
+
+        if let Some(change) = sub.wait_timeout(HEARTBEAT)? {
+            write_change(&mut writer, &change)?;
+        }
+    }
+}
+
+fn write_change<W: Write>(w: &mut W, change: &Change) -> Result<()> {
+    let flags = match change.kind {
+        ChangeKind::Put => 0,
+        ChangeKind::Delete => FLAG_TOMBSTONE,
+        ChangeKind::Reset => {
+            w.write_u8(RESET)?;
+            write_position(w, change.position)?;
+            write_position(w, change.resume)?;
+            return Ok(());
+        }
+    };
+    let buf = record::encode_expiring(Version::V3, &change.kv.key, &change.kv.value, flags, change.expires_at)?;
+    w.write_u8(CHANGE)?;
+    write_position(w, change.position)?;
+    write_position(w, change.resume)?;
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+fn write_position<W: Write>(w: &mut W, position: Position) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(position.segment)?;
+    w.write_u64::<LittleEndian>(position.offset)
+}
+
+fn read_position<R: Read>(r: &mut R) -> io::Result<Position> {
+    let segment = r.read_u32::<LittleEndian>()?;
+    let offset = r.read_u64::<LittleEndian>()?;
+    Ok(Position::new(segment, offset))
+}
+
+/// Applies a leader's log to a store of its own.
+pub struct Follower {
+    store: SharedKV,
+    state: File,
+    /// Where to pick up after the last change applied.
+    position: Position,
+    /// The ops of a write batch read so far, applied together once its
+    /// last op arrives.
+    batch: WriteBatch,
+    /// After a reset: the keys the leader hasn't sent since.
+    unseen: Option<HashSet<ByteString>>,
+}
+
+impl Follower {
+    /// Follows into `store`, carrying on from where the last follower of
+    /// this store got to.
+    pub fn new(store: SharedKV) -> Result<Self> {
+        let path = replica_path(&store.read().path);
+        let mut state = OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .truncate(false)
+                            .open(path)?;
+
+        let mut buf = Vec::new();
+        state.read_to_end(&mut buf)?;
+        // 没有或者坏了就从头来，重放一遍结果还是对的
+        let position = match buf.len() {
+            16 if crc32::checksum_ieee(&buf[..12]) == (&buf[12..]).read_u32::<LittleEndian>()? => {
+                read_position(&mut &buf[..12])?
+            }
+            _ => Position::default(),
+        };
+
+        Ok(Follower { store, state, position, batch: WriteBatch::new(), unseen: None })
+    }
+
+    /// The store changes are applied to. Clone it to serve reads while the
+    /// follower runs.
+    pub fn store(&self) -> &SharedKV {
+        &self.store
+    }
+
+    /// The position in the leader's log that the follower has reached.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Applies the leader's changes as they come, until it closes the
+    /// connection.
+    pub fn follow<A: ToSocketAddrs>(&mut self, leader: A) -> Result<()> {
+        self.stream(leader, false)
+    }
+
+    /// Applies the leader's changes until the follower has everything the
+    /// leader had when it connected, or more.
+    pub fn catch_up<A: ToSocketAddrs>(&mut self, leader: A) -> Result<()> {
+        self.stream(leader, true)
+    }
+
+    fn stream<A: ToSocketAddrs>(&mut self, leader: A, until_caught_up: bool) -> Result<()> {
+        let stream = TcpStream::connect(leader)?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        writer.write_all(MAGIC)?;
+        write_position(&mut writer, self.position)?;
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        // 中途断开的 batch 下次会从头重发
+        self.batch.clear();
+        loop {
+            let tag = match reader.read_u8() {
+                Ok(tag) => tag,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            match tag {
+                CHANGE => self.apply(&mut reader)?,
+                RESET => {
+                    let _ = read_position(&mut reader)?;
+                    let _ = read_position(&mut reader)?;
+                    self.unseen = Some(self.store.read().keys().map(|key| key.to_vec()).collect());
+                }
+                CAUGHT_UP => {
+                    if let Some(unseen) = self.unseen.take() {
+                        for key in unseen {
+                            self.store.delete(&key)?;
+                        }
+                        self.save()?;
+                    }
+                    if until_caught_up {
+                        return Ok(());
+                    }
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown replication message {:#04x}", other),
+                    ).into());
+                }
+            }
+        }
+    }
+
+    fn apply<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let position = read_position(reader)?;
+        let resume = read_position(reader)?;
+        let rec = record::decode(reader, Version::V3, position.offset, &Codec::default())?;
+
+        if let Some(unseen) = self.unseen.as_mut() {
+            unseen.remove(&rec.kv.key);
+        }
+
+        // batch 里除了最后一条，resume 都指回 batch 开头
+        let batched = resume <= position;
+        if batched || !self.batch.is_empty() {
+            if rec.is_tombstone() {
+                self.batch.delete(&rec.kv.key);
+            } else {
+                self.batch.insert(&rec.kv.key, &rec.kv.value);
+            }
+            if batched {
+                return Ok(());
+            }
+            self.store.write_batch(&self.batch)?;
+            self.batch.clear();
+        } else if rec.is_tombstone() {
+            self.store.delete(&rec.kv.key)?;
+        } else {
+            self.store.write().insert_expiring(&rec.kv.key, &rec.kv.value, rec.expires_at)?;
+        }
+
+        self.position = resume;
+        // reset 之后删完没再出现的 key 才算跟上，在那之前不记位置
+        if self.unseen.is_none() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        write_position(&mut buf, self.position)?;
+        let checksum = crc32::checksum_ieee(&buf);
+        buf.write_u32::<LittleEndian>(checksum)?;
+
+        self.state.seek(SeekFrom::Start(0))?;
+        self.state.write_all(&buf)
+    }
+}
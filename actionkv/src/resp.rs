@@ -0,0 +1,185 @@
+//! The subset of RESP (the Redis protocol) spoken by `akv_server`.
+//!
+//! Requests are arrays of bulk strings, the way every Redis client sends
+//! them:
+//!
+//! *3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n
+//!
+//! Replies use simple strings (`+OK`), errors (`-ERR ...`), integers
+//! (`:1`), bulk strings (`$1\r\n1`, or `$-1` for a missing value) and
+//! arrays of bulk strings. See `server` for the commands.
+//!
+//! The server reads requests with `read_request`, which only takes a flat
+//! array of bulk strings, so a client can't nest arrays to run it out of
+//! stack. `read_value` reads anything and is for replies.
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::ByteString;
+
+/// Longest bulk string we accept, so a bad length can't make us allocate
+/// the world.
+pub(crate) const MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+/// Most elements we accept in one array.
+pub(crate) const MAX_ARRAY_LEN: u64 = 1024 * 1024;
+/// Most bulk string bytes we accept in one request, all arguments
+/// together.
+pub(crate) const MAX_REQUEST_LEN: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<ByteString>),
+    Array(Vec<Value>),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads one `\r\n` terminated line, without the terminator. `None` at a
+/// clean end of stream.
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<ByteString>> {
+    let mut line = Vec::new();
+    if r.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("bad integer"))
+}
+
+/// Reads one value. `None` if the stream ended before it started.
+pub(crate) fn read_value<R: BufRead>(r: &mut R) -> io::Result<Option<Value>> {
+    let line = match read_line(r)? {
+        Some(line) if !line.is_empty() => line,
+        Some(_) => return Err(invalid("empty line")),
+        None => return Ok(None),
+    };
+    let (kind, rest) = (line[0], &line[1..]);
+
+    let value = match kind {
+        b'+' => Value::Simple(String::from_utf8_lossy(rest).into_owned()),
+        b'-' => Value::Error(String::from_utf8_lossy(rest).into_owned()),
+        b':' => Value::Integer(parse_int(rest)?),
+        b'$' => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                return Ok(Some(Value::Bulk(None)));
+            }
+            if len as u64 > MAX_BULK_LEN {
+                return Err(invalid("bulk string too long"));
+            }
+            Value::Bulk(Some(read_bulk(r, len as u64)?))
+        }
+        b'*' => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                return Ok(Some(Value::Array(Vec::new())));
+            }
+            if len as u64 > MAX_ARRAY_LEN {
+                return Err(invalid("array too long"));
+            }
+            let mut items = Vec::with_capacity(len.min(64) as usize);
+            for _ in 0..len {
+                match read_value(r)? {
+                    Some(item) => items.push(item),
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+            Value::Array(items)
+        }
+        _ => return Err(invalid("unknown value type")),
+    };
+    Ok(Some(value))
+}
+
+/// Reads one request, which must be an array of bulk strings, and
+/// returns its elements. Nested arrays and other kinds of value are an
+/// error. `None` if the stream ended before the request started.
+pub(crate) fn read_request<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<ByteString>>> {
+    let line = match read_line(r)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let len = match line.split_first() {
+        Some((b'*', rest)) => parse_int(rest)?,
+        _ => return Err(invalid("expected an array of bulk strings")),
+    };
+    if len < 0 {
+        return Ok(Some(Vec::new()));
+    }
+    if len as u64 > MAX_ARRAY_LEN {
+        return Err(invalid("array too long"));
+    }
+
+    // 元素个数是客户端说的，别照着它预先分配
+    let mut args = Vec::with_capacity(len.min(64) as usize);
+    let mut total = 0;
+    for _ in 0..len {
+        let line = read_line(r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = match line.split_first() {
+            Some((b'$', rest)) => parse_int(rest)?,
+            _ => return Err(invalid("expected an array of bulk strings")),
+        };
+        if len < 0 {
+            return Err(invalid("expected an array of bulk strings"));
+        }
+        total += len as u64;
+        if len as u64 > MAX_BULK_LEN || total > MAX_REQUEST_LEN {
+            return Err(invalid("request too long"));
+        }
+        args.push(read_bulk(r, len as u64)?);
+    }
+    Ok(Some(args))
+}
+
+/// The `len` bytes of a bulk string and the `\r\n` after them.
+fn read_bulk<R: BufRead>(r: &mut R, len: u64) -> io::Result<ByteString> {
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    let mut crlf = [0u8; 2];
+    r.read_exact(&mut crlf)?;
+    if data.len() as u64 != len || &crlf != b"\r\n" {
+        return Err(invalid("bad bulk string"));
+    }
+    Ok(data)
+}
+
+pub(crate) fn write_value<W: Write>(w: &mut W, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Simple(s) => write!(w, "+{}\r\n", s),
+        Value::Error(s) => write!(w, "-{}\r\n", s),
+        Value::Integer(n) => write!(w, ":{}\r\n", n),
+        Value::Bulk(None) => w.write_all(b"$-1\r\n"),
+        Value::Bulk(Some(data)) => {
+            write!(w, "${}\r\n", data.len())?;
+            w.write_all(data)?;
+            w.write_all(b"\r\n")
+        }
+        Value::Array(items) => {
+            write!(w, "*{}\r\n", items.len())?;
+            for item in items {
+                write_value(w, item)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A request: the command name followed by its arguments.
+pub(crate) fn command(args: &[&[u8]]) -> Value {
+    Value::Array(args.iter().map(|arg| Value::Bulk(Some(arg.to_vec()))).collect())
+}
@@ -0,0 +1,110 @@
+//! Secondary indexes: terms pulled out of values by an extractor, each
+//! mapped to the keys whose values have it. They live in memory only, and
+//! `load` rebuilds them by reading every live value. See
+//! `ActionKV::add_index`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{ByteStr, ByteString};
+
+/// Turns a value into the terms to index it under. Values it returns no
+/// terms for are left out of the index.
+pub(crate) type Extractor = Arc<dyn Fn(&ByteStr) -> Vec<ByteString> + Send + Sync>;
+
+struct SecondaryIndex {
+    extract: Extractor,
+    /// term -> keys, in key order so lookups come back sorted
+    keys: HashMap<ByteString, BTreeSet<ByteString>>,
+    /// key -> terms, so that a key can be taken out without reading its
+    /// old value back
+    terms: HashMap<ByteString, Vec<ByteString>>,
+}
+
+impl SecondaryIndex {
+    fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.remove(key);
+
+        let mut terms = (self.extract)(value);
+        terms.sort_unstable();
+        terms.dedup();
+        if terms.is_empty() {
+            return;
+        }
+        for term in &terms {
+            self.keys.entry(term.clone()).or_default().insert(key.to_vec());
+        }
+        self.terms.insert(key.to_vec(), terms);
+    }
+
+    fn remove(&mut self, key: &ByteStr) {
+        for term in self.terms.remove(key).unwrap_or_default() {
+            if let Some(keys) = self.keys.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&term);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SecondaryIndexes {
+    by_name: HashMap<String, SecondaryIndex>,
+}
+
+impl SecondaryIndexes {
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Adds an empty index, replacing any with the same name.
+    pub fn add(&mut self, name: &str, extract: Extractor) {
+        let index = SecondaryIndex { extract, keys: HashMap::new(), terms: HashMap::new() };
+        self.by_name.insert(name.to_string(), index);
+    }
+
+    /// Indexes `value` under `key` in every index, in place of what was
+    /// there for `key` before.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        for index in self.by_name.values_mut() {
+            index.insert(key, value);
+        }
+    }
+
+    /// Indexes `value` under `key` in the index called `name` only.
+    pub fn insert_into(&mut self, name: &str, key: &ByteStr, value: &ByteStr) {
+        if let Some(index) = self.by_name.get_mut(name) {
+            index.insert(key, value);
+        }
+    }
+
+    pub fn remove(&mut self, key: &ByteStr) {
+        for index in self.by_name.values_mut() {
+            index.remove(key);
+        }
+    }
+
+    /// Empties every index, keeping the extractors.
+    pub fn clear(&mut self) {
+        for index in self.by_name.values_mut() {
+            index.keys.clear();
+            index.terms.clear();
+        }
+    }
+
+    /// The keys indexed under `term` in the index called `name`, in key
+    /// order. `None` if there is no such index.
+    pub fn get(&self, name: &str, term: &ByteStr) -> Option<impl Iterator<Item = &ByteString>> {
+        let index = self.by_name.get(name)?;
+        Some(index.keys.get(term).into_iter().flatten())
+    }
+}
+
+impl fmt::Debug for SecondaryIndexes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.by_name.keys()).finish()
+    }
+}
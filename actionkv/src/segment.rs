@@ -0,0 +1,185 @@
+//! Bitcask-style segments.
+//!
+//! A store named `FILE` is a series of segment files. Segment 0 is `FILE`
+//! itself, so logs from before segments existed open as a one-segment
+//! store; later segments are `FILE.000001`, `FILE.000002`, ... Only the
+//! highest-numbered segment, the active one, is ever appended to. The others
+//! are sealed and opened read-only the first time something reads them.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::error::Result;
+#[cfg(feature = "mmap")]
+use crate::mmap::SegmentMap;
+use crate::record::{self, Record, Version};
+use crate::ByteStr;
+
+/// Where a record lives. Orders the same way the log was written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Position {
+    pub segment: u32,
+    pub offset: u64,
+}
+
+impl Position {
+    pub fn new(segment: u32, offset: u64) -> Self {
+        Position { segment, offset }
+    }
+}
+
+pub(crate) fn segment_path(base: &Path, id: u32) -> PathBuf {
+    if id == 0 {
+        return base.to_path_buf();
+    }
+    let mut name: OsString = base.as_os_str().to_owned();
+    name.push(format!(".{:06}", id));
+    PathBuf::from(name)
+}
+
+/// Ids of the segments that exist on disk, lowest first.
+pub(crate) fn list_segments(base: &Path) -> io::Result<Vec<u32>> {
+    let dir = match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = match base.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file path")),
+    };
+
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name == file_name {
+            ids.push(0);
+            continue;
+        }
+        let suffix = match name.strip_prefix(&file_name).and_then(|s| s.strip_prefix('.')) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        if suffix.len() == 6 && suffix.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(id) = suffix.parse() {
+                ids.push(id);
+            }
+        }
+    }
+
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Reads a file from `offset` on with positional reads, leaving the file's
+/// own cursor alone, so any number of threads can read through the same
+/// handle at once.
+pub(crate) struct ReadAt<'a> {
+    f: &'a File,
+    offset: u64,
+}
+
+impl<'a> ReadAt<'a> {
+    pub fn new(f: &'a File, offset: u64) -> Self {
+        ReadAt { f, offset }
+    }
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(self.f, buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(f, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(f, buf, offset)
+}
+
+#[derive(Debug)]
+pub(crate) struct Segment {
+    pub id: u32,
+    pub path: PathBuf,
+    reader: OnceLock<(File, Version)>,
+    /// Where `Options::mmap` reads from. See `mmap`.
+    #[cfg(feature = "mmap")]
+    pub map: SegmentMap,
+}
+
+impl Segment {
+    pub fn new(base: &Path, id: u32) -> Self {
+        Segment {
+            id,
+            path: segment_path(base, id),
+            reader: OnceLock::new(),
+            #[cfg(feature = "mmap")]
+            map: SegmentMap::default(),
+        }
+    }
+
+    /// A read-only handle on the segment and the format it is written in,
+    /// opened on first use.
+    pub fn reader(&self) -> Result<(&File, Version)> {
+        if let Some((f, version)) = self.reader.get() {
+            return Ok((f, *version));
+        }
+
+        let mut f = File::open(&self.path)?;
+        let version = record::read_file_header(&mut f)?;
+        f.seek(SeekFrom::Start(0))?;
+        let _ = self.reader.set((f, version));
+
+        let (f, version) = self.reader.get().unwrap();
+        Ok((f, *version))
+    }
+}
+
+/// Segment `id` out of `segments`, which are sorted by id.
+pub(crate) fn find(segments: &[Arc<Segment>], id: u32) -> Result<&Segment> {
+    match segments.binary_search_by_key(&id, |s| s.id) {
+        Ok(i) => Ok(&segments[i]),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no segment {}", id),
+        ).into()),
+    }
+}
+
+/// Reads the record at `position`. Safe to call from many threads at once.
+pub(crate) fn read_record(
+    segments: &[Arc<Segment>],
+    position: Position,
+    codec: &Codec,
+) -> Result<Record> {
+    let (f, version) = find(segments, position.segment)?.reader()?;
+    let mut f = BufReader::new(ReadAt::new(f, position.offset));
+    record::decode(&mut f, version, position.offset, codec)
+}
+
+/// Fails unless the record found at `position` is for `key`, rather than
+/// hand back another key's value from an index entry that went stale.
+pub(crate) fn expect_key(position: Position, key: &ByteStr, found: &ByteStr) -> Result<()> {
+    if key == found {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "index entry for {:?} points at another key's record (segment {}, offset {})",
+            String::from_utf8_lossy(key), position.segment, position.offset
+        ),
+    ).into())
+}
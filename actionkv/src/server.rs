@@ -0,0 +1,135 @@
+//! Serving a store over TCP.
+//!
+//! Only one process at a time can have a log open for writing (see `lock`),
+//! so services that need to share a store go through one `akv_server`
+//! instead. It speaks the RESP subset described in `resp`, one thread per
+//! connection, with these commands:
+//!
+//! | command            | reply                                              |
+//! |--------------------|----------------------------------------------------|
+//! | `GET key`          | the value, or a null bulk string                   |
+//! | `SET key value`    | `+OK`                                              |
+//! | `DEL key [key...]` | how many of the keys existed                       |
+//! | `SCAN [prefix]`    | `key, value, key, value, ...` in key order         |
+//! | `PING`             | `+PONG`                                            |
+//!
+//! Command names are case-insensitive. `SCAN` is not Redis' cursor-based
+//! one: it returns every match in a single reply. A server made
+//! `read_only`, e.g. in front of a replication follower, answers `SET` and
+//! `DEL` with a `-READONLY` error.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+use crate::resp::{self, Value};
+use crate::{ByteString, SharedKV};
+
+pub struct Server {
+    listener: TcpListener,
+    store: SharedKV,
+    read_only: bool,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: SharedKV) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Server { listener, store, read_only: false })
+    }
+
+    /// Refuse writes from clients. For a store that something else writes
+    /// to, like a `Follower`'s.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Where the server is listening. Useful after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails, serving each on its own
+    /// thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let store = self.store.clone();
+            let read_only = self.read_only;
+            thread::spawn(move || {
+                // 连接断了就断了，不影响别的连接
+                let _ = serve(stream, &store, read_only);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve(stream: TcpStream, store: &SharedKV, read_only: bool) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match resp::read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                let reply = Value::Error(format!("ERR Protocol error: {}", err));
+                resp::write_value(&mut writer, &reply)?;
+                return writer.flush();
+            }
+        };
+
+        let reply = if args.is_empty() {
+            Value::Error("ERR expected an array of bulk strings".to_string())
+        } else {
+            execute(store, &args, read_only)
+        };
+        resp::write_value(&mut writer, &reply)?;
+        writer.flush()?;
+    }
+}
+
+fn wrong_arity(name: &str) -> Value {
+    Value::Error(format!("ERR wrong number of arguments for '{}'", name))
+}
+
+fn execute(store: &SharedKV, args: &[ByteString], read_only: bool) -> Value {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    if read_only && matches!(name.as_str(), "SET" | "DEL") {
+        return Value::Error("READONLY You can't write against a read only replica.".to_string());
+    }
+    let result = match (name.as_str(), &args[1..]) {
+        ("PING", []) => Ok(Value::Simple("PONG".to_string())),
+        ("GET", [key]) => store.get(key).map(Value::Bulk),
+        ("SET", [key, value]) => store.insert(key, value).map(|_| Value::Simple("OK".to_string())),
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut store = store.write();
+            let mut deleted = 0;
+            keys.iter()
+                .try_for_each(|key| {
+                    if store.contains_key(key)? {
+                        deleted += 1;
+                        store.delete(key)?;
+                    }
+                    Ok(())
+                })
+                .map(|_| Value::Integer(deleted))
+        }
+        ("SCAN", rest) if rest.len() <= 1 => {
+            let prefix: &[u8] = rest.first().map(|p| p.as_slice()).unwrap_or(b"");
+            let store = store.read();
+            store
+                .prefix(prefix)
+                .map(|kv| kv.map(|kv| [Value::Bulk(Some(kv.key)), Value::Bulk(Some(kv.value))]))
+                .collect::<crate::Result<Vec<_>>>()
+                .map(|pairs| Value::Array(pairs.into_iter().flatten().collect()))
+        }
+        ("PING" | "GET" | "SET" | "DEL" | "SCAN", _) => return wrong_arity(&name),
+        _ => return Value::Error(format!("ERR unknown command '{}'", name)),
+    };
+
+    result.unwrap_or_else(|err| Value::Error(format!("ERR {}", err)))
+}
@@ -0,0 +1,182 @@
+//! A store that can be shared between threads.
+//!
+//! `SharedKV` is a cheap, cloneable handle on one `ActionKV`. Reads take a
+//! shared lock and use positional reads, so any number of them run at once;
+//! writes take the lock exclusively and so go one at a time.
+
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::{
+    ActionKV, ByteStr, ByteString, GroupCommit, KeyValuePair, Options, Position, Snapshot, Subscription, SyncPolicy,
+    ValueRef, WriteBatch,
+};
+
+#[derive(Debug, Clone)]
+pub struct SharedKV {
+    inner: Arc<RwLock<ActionKV>>,
+}
+
+impl SharedKV {
+    /// Opens the store at `path` and loads its index.
+    pub fn open(path: &Path) -> Result<Self> {
+        SharedKV::open_with(path, &Options::default())
+    }
+
+    pub fn open_with(path: &Path, options: &Options) -> Result<Self> {
+        let mut store = options.open(path)?;
+        store.load()?;
+        Ok(SharedKV::new(store))
+    }
+
+    /// Shares `store`. With `SyncPolicy::EveryInterval`, also starts a
+    /// thread that syncs writes left waiting for the interval, which stops
+    /// once every handle is dropped.
+    pub fn new(store: ActionKV) -> Self {
+        let interval = match store.options.sync {
+            SyncPolicy::EveryInterval(interval) => Some(interval),
+            _ => None,
+        };
+        let shared = SharedKV { inner: Arc::new(RwLock::new(store)) };
+        if let Some(interval) = interval {
+            let store = Arc::downgrade(&shared.inner);
+            thread::spawn(move || sync_every(store, interval));
+        }
+        shared
+    }
+
+    /// The store behind a shared lock, for scans and anything else that
+    /// only reads. Writers wait until the guard is dropped.
+    pub fn read(&self) -> RwLockReadGuard<'_, ActionKV> {
+        // 写的时候 panic 不会留下写了一半的 index，接着用
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The store behind an exclusive lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, ActionKV> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.read().get(key)
+    }
+
+    /// See `ActionKV::get_ref`. The value stays readable after the lock is
+    /// released.
+    pub fn get_ref(&self, key: &ByteStr) -> Result<Option<ValueRef>> {
+        self.read().get_ref(key)
+    }
+
+    /// See `ActionKV::contains_key`.
+    pub fn contains_key(&self, key: &ByteStr) -> Result<bool> {
+        self.read().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// See `ActionKV::add_index`. Holds the lock while the index is filled.
+    pub fn add_index<F>(&self, name: &str, extract: F) -> Result<()>
+    where
+        F: Fn(&ByteStr) -> Vec<ByteString> + Send + Sync + 'static,
+    {
+        self.write().add_index(name, extract)
+    }
+
+    /// See `ActionKV::find_by`.
+    pub fn find_by(&self, name: &str, term: &ByteStr) -> Result<Vec<KeyValuePair>> {
+        self.read().find_by(name, term)?.collect()
+    }
+
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write().insert(key, value)
+    }
+
+    pub fn insert_with_ttl(&self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+        self.write().insert_with_ttl(key, value, ttl)
+    }
+
+    pub fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write().update(key, value)
+    }
+
+    pub fn delete(&self, key: &ByteStr) -> Result<()> {
+        self.write().delete(key)
+    }
+
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.write().write_batch(batch)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.write().flush()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.write().sync()
+    }
+
+    pub fn write_hint(&self) -> Result<()> {
+        self.write().write_hint()
+    }
+
+    /// Starts a group-commit writer thread for this store. See
+    /// `GroupCommit`.
+    pub fn group_commit(&self) -> GroupCommit {
+        GroupCommit::new(self.clone())
+    }
+
+    /// A snapshot of the store. Only holds the lock while the index is
+    /// copied.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.read().snapshot()
+    }
+
+    /// Backs the store up to `path` while writers carry on. See
+    /// `Snapshot::export_to`.
+    pub fn export_to(&self, path: &Path) -> Result<()> {
+        self.snapshot()?.export_to(path)
+    }
+
+    /// Follows the log from `from`. See `ActionKV::subscribe`.
+    pub fn subscribe(&self, from: Position) -> Subscription {
+        self.read().subscribe(from)
+    }
+
+    /// Compacts the log. The copy runs without holding the lock, so reads
+    /// and writes carry on until the final swap.
+    pub fn compact(&self) -> Result<()> {
+        let mut compaction = self.write().start_compaction()?;
+        compaction.run()?;
+        self.write().finish_compaction(compaction)
+    }
+}
+
+impl From<ActionKV> for SharedKV {
+    fn from(store: ActionKV) -> Self {
+        SharedKV::new(store)
+    }
+}
+
+/// Syncs `store` every `interval` if writes are waiting, until the last
+/// `SharedKV` on it is gone.
+fn sync_every(store: Weak<RwLock<ActionKV>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => return,
+        };
+        let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+        // 失败了也没人可报，下一次写或 sync 会再试一次并报错
+        let _ = store.sync_if_due();
+    }
+}
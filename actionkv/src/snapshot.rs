@@ -0,0 +1,143 @@
+//! Point-in-time views of a store.
+//!
+//! The log is append-only, so a snapshot is just a frozen copy of the index
+//! plus the position the log had reached: nothing it points at is ever
+//! overwritten. It holds its own handles on the segments, so it stays
+//! readable while the store goes on writing, and even after a compaction
+//! has removed the files it was reading (on platforms that let open files
+//! be removed).
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::codec::Codec;
+use crate::compact::{compact_path, sync_dir};
+use crate::error::Result;
+use crate::hint;
+use crate::index::Index;
+use crate::record::{self, Version};
+use crate::segment::{self, Position, Segment};
+use crate::{bounds, ByteStr, ByteString, Scan};
+
+/// A read-only view of a store as of `Snapshot::end`. See
+/// `ActionKV::snapshot`.
+#[derive(Debug)]
+pub struct Snapshot {
+    end: Position,
+    segments: Vec<Arc<Segment>>,
+    codec: Codec,
+    index: Index,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        end: Position,
+        segments: Vec<Arc<Segment>>,
+        codec: Codec,
+        index: Index,
+    ) -> Result<Self> {
+        // 现在就打开所有段，之后被压缩删掉也还能读
+        for segment in &segments {
+            segment.reader()?;
+        }
+        Ok(Snapshot { end, segments, codec, index })
+    }
+
+    /// Where the log ended when the snapshot was taken. Nothing written at
+    /// or after it is visible.
+    pub fn end(&self) -> Position {
+        self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let record = segment::read_record(&self.segments, position, &self.codec)?;
+        if record.is_expired(record::now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(record.kv.value))
+    }
+
+    /// Keys in key order. Like `ActionKV::keys`, includes keys whose TTL
+    /// has run out.
+    pub fn keys(&self) -> impl Iterator<Item = &ByteStr> + '_ {
+        self.index
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .map(|(key, _)| key.as_slice())
+    }
+
+    pub fn iter(&self) -> Scan<'_> {
+        Scan::new(&self.segments, &self.codec, self.index.range((Bound::Unbounded, Bound::Unbounded)))
+    }
+
+    pub fn range<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<ByteStr>,
+        R: RangeBounds<K>,
+    {
+        Scan::new(&self.segments, &self.codec, self.index.range(bounds(&range)))
+    }
+
+    pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Scan<'a> {
+        Scan::new(&self.segments, &self.codec, self.index.prefix(prefix))
+    }
+
+    /// Writes the snapshot's live pairs to a new store at `path`, one
+    /// segment with nothing but live records in it. The copy is written
+    /// with the same compression and encryption key, and is complete once
+    /// this returns: it only appears at `path` when it is synced.
+    pub fn export_to(&self, path: &Path) -> Result<()> {
+        if !segment::list_segments(path)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a store", path.display()),
+            ).into());
+        }
+
+        let tmp = compact_path(path);
+        let mut out = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?,
+        );
+        record::write_file_header(&mut out, Version::CURRENT)?;
+
+        let mut live: Vec<Position> = self.index.iter().map(|(_, position)| *position).collect();
+        live.sort_unstable();
+        let now = record::now_millis();
+        for position in live {
+            let rec = segment::read_record(&self.segments, position, &self.codec)?;
+            if rec.is_expired(now) {
+                continue;
+            }
+            let buf = self.codec.encode(Version::CURRENT, &rec.kv.key, &rec.kv.value, 0, rec.expires_at)?;
+            out.write_all(&buf)?;
+        }
+
+        let f: File = out.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        hint::remove(path)?;
+        fs::rename(&tmp, path)?;
+        sync_dir(path)?;
+        Ok(())
+    }
+}
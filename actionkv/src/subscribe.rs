@@ -0,0 +1,240 @@
+//! Following the log as it grows.
+//!
+//! A `Subscription` reads the segment files on its own, so it works whether
+//! the writer is this process or another one, and can be moved to another
+//! thread. It checks for new records every `poll_interval`.
+//!
+//! Delivery is at least once. `Subscription::position` is where to pick up
+//! after a restart to see everything not handed out yet; a write batch can
+//! only be read whole, so stopping halfway through one means seeing it
+//! again from the start. A compaction starts a new segment holding every
+//! live key, which a subscriber reads from the start like any other, so it
+//! sees repeats. If a compaction removes segments before a subscriber gets
+//! to them, deletes in them are lost, and the subscriber gets a
+//! `ChangeKind::Reset` instead.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::codec::Codec;
+use crate::error::Result;
+use crate::options::RecoveryPolicy;
+use crate::record::{self, Version};
+use crate::segment::{self, Position};
+use crate::walk::walk_segment;
+use crate::KeyValuePair;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Put,
+    Delete,
+    /// Segments the subscription had not read yet were compacted away. The
+    /// changes that follow replay every live key; keys that haven't come
+    /// round again by the time the subscription catches up were deleted.
+    Reset,
+}
+
+/// One put or delete read from the log. Deletes have an empty value, and
+/// resets an empty key as well.
+#[derive(Debug)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub kv: KeyValuePair,
+    /// Where the record is.
+    pub position: Position,
+    /// When a put stops being visible, in milliseconds since the Unix
+    /// epoch. See `ActionKV::insert_with_ttl`.
+    pub expires_at: Option<u64>,
+    /// `Subscription::position` once this change has been handed out.
+    pub resume: Position,
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    base: PathBuf,
+    codec: Codec,
+    /// Everything before this has been read into `queue`.
+    cursor: Position,
+    /// The segment `cursor` is in, kept open so that it can still be read
+    /// to the end after a compaction removes it.
+    current: Option<(File, Version)>,
+    queue: VecDeque<Change>,
+    handed_out: Position,
+    poll_interval: Duration,
+}
+
+impl Subscription {
+    pub(crate) fn new(base: &Path, codec: Codec, from: Position) -> Self {
+        Subscription {
+            base: base.to_path_buf(),
+            codec,
+            cursor: from,
+            current: None,
+            queue: VecDeque::new(),
+            handed_out: from,
+            poll_interval: Duration::from_millis(10),
+        }
+    }
+
+    /// How long `next` sleeps between looks at the log. 10ms by default.
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Where to subscribe from to carry on after the last change handed
+    /// out. Save it along with whatever was done with that change.
+    pub fn position(&self) -> Position {
+        self.handed_out
+    }
+
+    /// The next change if there is one, without waiting for it.
+    pub fn try_next(&mut self) -> Result<Option<Change>> {
+        if self.queue.is_empty() {
+            self.read_more()?;
+        }
+        let change = self.queue.pop_front();
+        if let Some(change) = &change {
+            self.handed_out = change.resume;
+        }
+        Ok(change)
+    }
+
+    /// Waits for the next change.
+    pub fn wait(&mut self) -> Result<Change> {
+        loop {
+            if let Some(change) = self.try_next()? {
+                return Ok(change);
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Like `wait`, but gives up after `timeout`.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<Change>> {
+        let mut waited = Duration::ZERO;
+        loop {
+            if let Some(change) = self.try_next()? {
+                return Ok(Some(change));
+            }
+            if waited >= timeout {
+                return Ok(None);
+            }
+            thread::sleep(self.poll_interval);
+            waited += self.poll_interval;
+        }
+    }
+
+    /// Reads what has been appended to the current segment since last
+    /// time, moving on to the next segment once this one is sealed.
+    fn read_more(&mut self) -> Result<()> {
+        loop {
+            if self.current.is_none() && !self.open_current()? {
+                return Ok(());
+            }
+            self.read_current()?;
+            if !self.queue.is_empty() {
+                return Ok(());
+            }
+
+            let sealed = segment::list_segments(&self.base)?
+                .into_iter()
+                .any(|id| id > self.cursor.segment);
+            if !sealed {
+                return Ok(());
+            }
+            // 下一个段已经存在，当前段不会再变了；最后再读一遍，
+            // 免得漏掉看到下一个段之前刚写进来的记录
+            self.read_current()?;
+            self.current = None;
+            // 下一个段不是紧挨着的话，open_current 会报 Reset
+            self.cursor = Position::new(self.cursor.segment + 1, 0);
+            if !self.queue.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Opens the segment the cursor is in, or the first one after it if it
+    /// is gone. `false` if there is nothing to open yet.
+    fn open_current(&mut self) -> Result<bool> {
+        let ids = segment::list_segments(&self.base)?;
+        let id = match ids.into_iter().find(|id| *id >= self.cursor.segment) {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let mut f = match File::open(segment::segment_path(&self.base, id)) {
+            Ok(f) => f,
+            // 刚被压缩删掉，下次再找后面的段
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        // 新段刚建好、文件头还没写完：等下次
+        let mut head = Vec::new();
+        (&f).take(record::FILE_HEADER_LEN).read_to_end(&mut head)?;
+        if head.is_empty() || record::is_torn_file_header(&head) {
+            return Ok(false);
+        }
+        f.seek(SeekFrom::Start(0))?;
+        let version = record::read_file_header(&mut f)?;
+
+        if id != self.cursor.segment {
+            let at = Position::new(id, 0);
+            self.queue.push_back(Change {
+                kind: ChangeKind::Reset,
+                kv: KeyValuePair { key: Vec::new(), value: Vec::new() },
+                position: at,
+                expires_at: None,
+                resume: at,
+            });
+            self.cursor = at;
+        }
+        self.current = Some((f, version));
+        Ok(true)
+    }
+
+    fn read_current(&mut self) -> Result<()> {
+        let (f, version) = match &self.current {
+            Some((f, version)) => (f, *version),
+            None => return Ok(()),
+        };
+        let id = self.cursor.segment;
+        let start = self.cursor.offset.max(version.data_start());
+        let queue = &mut self.queue;
+
+        let walked = walk_segment(
+            &mut BufReader::new(f),
+            version,
+            start,
+            RecoveryPolicy::Fail,
+            &self.codec,
+            |offset, rec, resume| {
+                let kind = if rec.is_tombstone() { ChangeKind::Delete } else { ChangeKind::Put };
+                queue.push_back(Change {
+                    kind,
+                    position: Position::new(id, offset),
+                    expires_at: rec.expires_at,
+                    kv: rec.kv,
+                    resume: Position::new(id, resume),
+                });
+                Ok(())
+            },
+        );
+        self.cursor.offset = walked?.good_end;
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Change>;
+
+    /// Blocks until the next change. Never returns `None`.
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.wait())
+    }
+}
@@ -0,0 +1,177 @@
+//! Typed keys and values on top of the byte-oriented store.
+//!
+//! `TypedStore` serializes keys and values with one `Format` and hands them
+//! to an `ActionKV`, so every service that reads the store with the same
+//! format sees the same types. The log itself still only holds bytes:
+//! nothing on disk records which format was used, and opening a store with
+//! the wrong one shows up as `ActionKvError::Serialization` on read.
+//!
+//! Keys are compared as encoded bytes, so `iter` comes back in the order of
+//! those bytes. For bincode that is not numeric order for integers (they
+//! are little-endian), and for JSON it is not numeric order for numbers of
+//! different lengths.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use bincode::Options as _;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{ActionKvError, Result};
+use crate::{ActionKV, ByteStr, ByteString, Scan};
+
+/// How `TypedStore` turns keys and values into bytes. Formats other than
+/// `Format::Bincode` need the cargo feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// bincode 1.x as `bincode::serialize` writes it: fixed-width
+    /// little-endian integers, lengths as u64. Decoding rejects trailing
+    /// bytes, so a value read back as the wrong type fails rather than
+    /// decoding a prefix of it.
+    #[default]
+    Bincode,
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<ByteString> {
+        match self {
+            Format::Bincode => bincode::serialize(value).map_err(|e| serialization(self, e)),
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::to_vec(value).map_err(|e| serialization(self, e)),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| serialization(self, e))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &ByteStr) -> Result<T> {
+        match self {
+            Format::Bincode => bincode_options().deserialize(bytes).map_err(|e| serialization(self, e)),
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| serialization(self, e)),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| serialization(self, e)),
+        }
+    }
+}
+
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes()
+}
+
+fn serialization(format: Format, err: impl fmt::Display) -> ActionKvError {
+    ActionKvError::Serialization { format, message: err.to_string() }
+}
+
+/// An `ActionKV` whose keys are `K`s and values are `V`s. The raw store is
+/// still there (`store`, `store_mut`) for compaction, hints and the like.
+pub struct TypedStore<K, V> {
+    store: ActionKV,
+    format: Format,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedStore<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Wraps a store, which should already be loaded.
+    pub fn new(store: ActionKV, format: Format) -> Self {
+        TypedStore { store, format, _types: PhantomData }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn store(&self) -> &ActionKV {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut ActionKV {
+        &mut self.store
+    }
+
+    pub fn into_inner(self) -> ActionKV {
+        self.store
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = self.format.encode(key)?;
+        match self.store.get(&key)? {
+            Some(value) => self.format.decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        let key = self.format.encode(key)?;
+        self.store.contains_key(&key)
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = self.format.encode(key)?;
+        let value = self.format.encode(value)?;
+        self.store.insert(&key, &value)
+    }
+
+    /// See `ActionKV::insert_with_ttl`.
+    pub fn insert_with_ttl(&mut self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        let key = self.format.encode(key)?;
+        let value = self.format.encode(value)?;
+        self.store.insert_with_ttl(&key, &value, ttl)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        let key = self.format.encode(key)?;
+        self.store.delete(&key)
+    }
+
+    /// Every live pair, decoded, in the order of the encoded keys. See the
+    /// module docs.
+    pub fn iter(&self) -> TypedIter<'_, K, V> {
+        TypedIter { scan: self.store.iter(), format: self.format, _types: PhantomData }
+    }
+}
+
+impl<K, V> fmt::Debug for TypedStore<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedStore")
+            .field("store", &self.store)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+/// Decodes the pairs of a `Scan`. See `TypedStore::iter`.
+pub struct TypedIter<'a, K, V> {
+    scan: Scan<'a>,
+    format: Format,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Iterator for TypedIter<'_, K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let kv = match self.scan.next()? {
+            Ok(kv) => kv,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(self.format.decode(&kv.key).and_then(|key| Ok((key, self.format.decode(&kv.value)?))))
+    }
+}
@@ -0,0 +1,141 @@
+//! Reading a segment record by record, the way `load` sees it: batches are
+//! only handed out once their commit marker has been read, and bad records
+//! are dealt with according to the recovery policy.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+
+use crate::batch::PendingBatch;
+use crate::codec::Codec;
+use crate::error::{ActionKvError, Result};
+use crate::options::RecoveryPolicy;
+use crate::record::{self, Record, Version, FLAG_BATCHED, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT};
+use crate::segment::ReadAt;
+
+#[derive(Debug)]
+pub(crate) struct Walked {
+    /// End of the last record or committed batch that was read in full.
+    pub good_end: u64,
+    /// Where `RecoveryPolicy::Truncate` wants the segment cut.
+    pub truncate_at: Option<u64>,
+}
+
+/// Reads from `start` until the end of the segment, calling `visit` with
+/// each put or delete, its offset, and where to start reading again to see
+/// everything after it. That is the end of the record, except inside a
+/// batch, which can only be read whole: there it is the start of the batch
+/// until its last op, and the end of the batch after that.
+pub(crate) fn walk_segment<R, F>(
+    f: &mut R,
+    version: Version,
+    start: u64,
+    policy: RecoveryPolicy,
+    codec: &Codec,
+    mut visit: F,
+) -> Result<Walked>
+where
+    R: Read + Seek,
+    F: FnMut(u64, Record, u64) -> Result<()>,
+{
+    f.seek(SeekFrom::Start(start))?;
+    let mut good_end = start;
+    let mut pending: Option<PendingBatch<(u64, Record)>> = None;
+
+    loop {
+        let offset = f.stream_position()?;
+        let err = match record::decode(f, version, offset, codec) {
+            Ok(rec) if rec.has(FLAG_BATCH_BEGIN) => {
+                // 上一个 batch 没有提交就开始了新的：丢弃
+                pending = Some(PendingBatch::begin(offset, &rec.kv.value));
+                continue;
+            }
+            Ok(rec) if rec.has(FLAG_BATCHED) => {
+                if let Some(batch) = pending.as_mut() {
+                    batch.push(rec.checksum, (offset, rec));
+                }
+                continue;
+            }
+            Ok(rec) if rec.has(FLAG_BATCH_COMMIT) => {
+                let batch = match pending.take() {
+                    Some(batch) => batch,
+                    None => continue,
+                };
+                match batch.verify(&rec.kv.value) {
+                    Ok(()) => {
+                        let end = f.stream_position()?;
+                        let last = batch.ops.len().saturating_sub(1);
+                        for (i, (offset, op)) in batch.ops.into_iter().enumerate() {
+                            let resume = if i == last { end } else { batch.begin_offset };
+                            visit(offset, op, resume)?;
+                        }
+                        good_end = end;
+                        continue;
+                    }
+                    Err((expected, actual)) => ActionKvError::Corruption {
+                        offset: batch.begin_offset,
+                        expected,
+                        actual,
+                    },
+                }
+            }
+            Ok(rec) => {
+                pending = None;
+                good_end = f.stream_position()?;
+                visit(offset, rec, good_end)?;
+                continue;
+            }
+            Err(err) => err,
+        };
+
+        match err {
+            err if err.is_eof() => break,
+            ActionKvError::Corruption { .. } if policy == RecoveryPolicy::Skip => {
+                // 记录头里的长度还在，读过的字节已经跳过了这条记录
+                pending = None;
+            }
+            ActionKvError::Corruption { offset, .. } if policy == RecoveryPolicy::Truncate => {
+                let at = pending.map(|batch| batch.begin_offset).unwrap_or(offset);
+                return Ok(Walked { good_end, truncate_at: Some(at.min(offset)) });
+            }
+            err => return Err(err),
+        }
+    }
+
+    Ok(Walked { good_end, truncate_at: None })
+}
+
+/// `walk_segment` over `f` with `RecoveryPolicy::Skip`, also stepping over
+/// records that are whole but can't be decoded here: ones that fail
+/// authentication, need a key that wasn't given, or use a codec this build
+/// leaves out. `skipped` is told about each of those.
+pub(crate) fn walk_past_damage<F, S>(
+    f: &File,
+    version: Version,
+    start: u64,
+    codec: &Codec,
+    mut visit: F,
+    mut skipped: S,
+) -> Result<Walked>
+where
+    F: FnMut(u64, Record, u64) -> Result<()>,
+    S: FnMut(&ActionKvError),
+{
+    let mut start = start;
+    loop {
+        let walked = walk_segment(&mut BufReader::new(f), version, start, RecoveryPolicy::Skip, codec, &mut visit);
+        let offset = match &walked {
+            Err(
+                err @ (ActionKvError::Tampered { offset }
+                | ActionKvError::NoEncryptionKey { offset }
+                | ActionKvError::UnsupportedCodec { offset, .. }),
+            ) => {
+                skipped(err);
+                *offset
+            }
+            _ => return walked,
+        };
+        // 记录本身是完整的，从它后面接着读
+        start = offset + record::read_raw(&mut ReadAt::new(f, offset), version)?.len();
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use libactionkv::{exit, ActionKV};
+
+const AKV_DISK: &str = env!("CARGO_BIN_EXE_akv_disk");
+const AKV_MEM: &str = env!("CARGO_BIN_EXE_akv_mem");
+
+/// A fresh directory per test, so tests running at once don't share a store.
+fn store_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("actionkv-cli-tests")
+        .join(format!("{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("store")
+}
+
+fn run(bin: &str, args: &[&str]) -> Output {
+    Command::new(bin).args(args).output().unwrap()
+}
+
+fn run_with_stdin(bin: &str, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(bin)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn code(output: &Output) -> i32 {
+    output.status.code().unwrap()
+}
+
+fn hint_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".hint");
+    PathBuf::from(name)
+}
+
+#[test]
+fn akv_disk_exit_codes() {
+    let path = store_path("disk");
+    let file = path.to_str().unwrap();
+
+    assert_eq!(code(&run(AKV_DISK, &[])), exit::USAGE);
+    assert_eq!(code(&run(AKV_DISK, &[file, "get", "a"])), exit::NOT_FOUND);
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "a"])), exit::USAGE);
+    assert_eq!(code(&run(AKV_DISK, &[file, "frobnicate", "a"])), exit::USAGE);
+
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "a", "1"])), 0);
+    let got = run(AKV_DISK, &[file, "get", "a"]);
+    assert_eq!(code(&got), 0);
+    assert_eq!(got.stdout, b"1\n");
+
+    assert_eq!(code(&run(AKV_DISK, &[file, "update", "a", "2"])), 0);
+    assert_eq!(run(AKV_DISK, &[file, "get", "a"]).stdout, b"2\n");
+    assert_eq!(code(&run(AKV_DISK, &[file, "update", "b", "2"])), exit::NOT_FOUND);
+    assert_eq!(code(&run(AKV_DISK, &[file, "delete", "b"])), exit::NOT_FOUND);
+    assert_eq!(code(&run(AKV_DISK, &[file, "delete", "a"])), 0);
+    assert_eq!(code(&run(AKV_DISK, &[file, "get", "a"])), exit::NOT_FOUND);
+
+    // 有人开着写的时候不能再写，读没关系
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "c", "3"])), 0);
+    let writer = ActionKV::open(&path).unwrap();
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "d", "4"])), exit::LOCKED);
+    assert_eq!(code(&run(AKV_DISK, &[file, "get", "c"])), 0);
+    drop(writer);
+
+    // 坏在中间，不是崩溃留下的尾巴，写之前也修不掉：文件头之后第一条记录的 checksum
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[12] ^= 0xff;
+    fs::write(&path, bytes).unwrap();
+    assert_eq!(code(&run(AKV_DISK, &[file, "get", "c"])), exit::CORRUPT);
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "d", "4"])), exit::CORRUPT);
+}
+
+#[test]
+fn akv_disk_writes_the_hint_only_when_asked() {
+    let path = store_path("disk-hint");
+    let file = path.to_str().unwrap();
+
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "a", "1"])), 0);
+    assert!(!hint_path(&path).exists());
+
+    assert_eq!(code(&run(AKV_DISK, &[file, "hint"])), 0);
+    assert!(hint_path(&path).exists());
+    assert_eq!(code(&run(AKV_DISK, &[file, "hint", "extra"])), exit::USAGE);
+
+    // hint 之后的写入照样读得到
+    assert_eq!(code(&run(AKV_DISK, &[file, "insert", "b", "2"])), 0);
+    assert_eq!(run(AKV_DISK, &[file, "get", "a"]).stdout, b"1\n");
+    assert_eq!(run(AKV_DISK, &[file, "get", "b"]).stdout, b"2\n");
+}
+
+#[test]
+fn akv_mem_exit_codes() {
+    let path = store_path("mem");
+    let file = path.to_str().unwrap();
+
+    assert_eq!(code(&run(AKV_MEM, &[])), exit::USAGE);
+    assert_eq!(code(&run(AKV_MEM, &[file, "get", "a"])), exit::NOT_FOUND);
+    assert_eq!(code(&run(AKV_MEM, &[file, "insert", "a", "1", "extra"])), exit::USAGE);
+    assert_eq!(code(&run(AKV_MEM, &[file, "insert", "a", "1"])), 0);
+    assert_eq!(run(AKV_MEM, &[file, "get", "a"]).stdout, b"1\n");
+    assert_eq!(code(&run(AKV_MEM, &[file, "update", "b", "2"])), exit::NOT_FOUND);
+
+    let session = run_with_stdin(AKV_MEM, &["--session", file], "insert b two words\nget b\nget a\n");
+    assert_eq!(code(&session), 0);
+    assert_eq!(session.stdout, b"two words\n1\n");
+    // session 只改内存里的副本
+    assert_eq!(code(&run(AKV_MEM, &[file, "get", "b"])), exit::NOT_FOUND);
+
+    let session = run_with_stdin(AKV_MEM, &["--session"], "get a\ninsert a 1\n");
+    assert_eq!(code(&session), exit::NOT_FOUND);
+    let session = run_with_stdin(AKV_MEM, &["--session"], "insert a 1\nfrobnicate\nget a\n");
+    assert_eq!(code(&session), exit::USAGE);
+    assert!(session.stdout.is_empty());
+
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&path, bytes).unwrap();
+    assert_eq!(code(&run(AKV_MEM, &[file, "get", "a"])), exit::CORRUPT);
+    assert_eq!(code(&run_with_stdin(AKV_MEM, &["--session", file], "")), exit::CORRUPT);
+}